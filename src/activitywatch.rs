@@ -0,0 +1,411 @@
+use crate::source::{DataSource, LoadError, Loaded};
+use crate::history::{DatetimeRange, Day, History, InputStats, LoadOptions, Session};
+use chrono::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::BufReader;
+
+const AFK_BUCKET_TYPE: &str = "afkstatus";
+const INPUT_BUCKET_TYPE: &str = "os.hid.input";
+const NOT_AFK_STATUS: &str = "not-afk";
+
+#[derive(Debug)]
+pub enum ExportError {
+    Io(io::Error),
+    InvalidJson(serde_json::Error),
+    NoSupportedBuckets,
+    InvalidEvent { bucket: String, index: usize, reason: String },
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExportError::Io(error) => write!(f, "Failed to read file: {}", error),
+            ExportError::InvalidJson(error) => write!(f, "Not an ActivityWatch export: {}", error),
+            ExportError::NoSupportedBuckets => write!(f, "Not an ActivityWatch export: it has no afk or input buckets"),
+            ExportError::InvalidEvent { bucket, index, reason } => write!(f, "Bucket {}, event {}: {}", bucket, index, reason),
+        }
+    }
+}
+
+impl std::error::Error for ExportError {}
+
+impl From<io::Error> for ExportError {
+    fn from(error: io::Error) -> Self {
+        ExportError::Io(error)
+    }
+}
+
+/// The JSON file written by "Export all buckets as JSON" in ActivityWatch's web UI
+#[derive(Deserialize)]
+struct Export {
+    buckets: BTreeMap<String, Bucket>,
+}
+
+/// An export's bucket types without their events, which is enough to check it can be loaded.
+/// Events are skipped over while reading, so even large exports are checked without holding them in memory.
+#[derive(Deserialize)]
+struct ExportSummary {
+    buckets: BTreeMap<String, BucketSummary>,
+}
+
+#[derive(Deserialize)]
+struct BucketSummary {
+    #[serde(rename = "type")]
+    bucket_type: String,
+}
+
+fn check_bucket_types<'a>(mut bucket_types: impl Iterator<Item = &'a str>) -> Result<(), ExportError> {
+    if bucket_types.any(|bucket_type| bucket_type == AFK_BUCKET_TYPE || bucket_type == INPUT_BUCKET_TYPE) {
+        Ok(())
+    } else {
+        Err(ExportError::NoSupportedBuckets)
+    }
+}
+
+#[derive(Deserialize)]
+struct Bucket {
+    #[serde(rename = "type")]
+    bucket_type: String,
+    /// Left as JSON so one malformed event only skips that event
+    #[serde(default)]
+    events: Vec<Value>,
+}
+
+#[derive(Deserialize)]
+struct Event<D> {
+    timestamp: DateTime<Utc>,
+    /// Seconds
+    duration: f64,
+    data: D,
+}
+
+/// An aw-watcher-afk event, the user was either active or away for its whole duration
+#[derive(Deserialize)]
+struct AfkData {
+    status: String,
+}
+
+/// An aw-watcher-input event, counting the input seen during its duration
+#[derive(Deserialize)]
+struct InputData {
+    #[serde(default)]
+    presses: u64,
+    #[serde(default)]
+    clicks: u64,
+    #[serde(default, rename = "deltaX")]
+    delta_x: u64,
+    #[serde(default, rename = "deltaY")]
+    delta_y: u64,
+}
+
+/// The events of one workday, added up
+struct DayTotals {
+    start: DateTime<FixedOffset>,
+    end: DateTime<FixedOffset>,
+    active_seconds: f64,
+    movement: f64,
+    movement_seconds: f64,
+    clicks: u64,
+    keystrokes: u64,
+}
+
+impl DayTotals {
+    fn new(start: DateTime<FixedOffset>, end: DateTime<FixedOffset>) -> DayTotals {
+        DayTotals {
+            start,
+            end,
+            active_seconds: 0.0,
+            movement: 0.0,
+            movement_seconds: 0.0,
+            clicks: 0,
+            keystrokes: 0,
+        }
+    }
+
+    fn into_session(self) -> Session {
+        Session {
+            datetime_range: DatetimeRange::new(self.start, self.end),
+            stats: InputStats {
+                total_active_time_seconds: self.active_seconds.round() as u64,
+                total_mouse_movement: self.movement.round() as u64,
+                total_mouse_click_movement: 0,
+                total_mouse_movement_time: self.movement_seconds.round() as u64,
+                total_mouse_clicks: self.clicks,
+                total_keystrokes: self.keystrokes,
+            },
+            breaks: BTreeMap::new(),
+            source: None,
+        }
+    }
+}
+
+/// A JSON bucket export from ActivityWatch, read offline without its server.
+/// Active time comes from aw-watcher-afk buckets and input counts from aw-watcher-input buckets,
+/// each workday becomes one session spanning its first to last active or input event.
+pub struct ActivityWatchSource;
+
+impl DataSource for ActivityWatchSource {
+    fn name(&self) -> &'static str {
+        "ActivityWatch"
+    }
+
+    fn load_history(&self, path: &str, options: &LoadOptions) -> Result<Loaded, LoadError> {
+        Ok(ActivityWatchSource::convert(ActivityWatchSource::read_export(path)?, options)?)
+    }
+
+    /// ActivityWatch has no separate file for the current day, an export of just today loads the same way
+    fn load_today(&self, path: &str, options: &LoadOptions) -> Result<Loaded, LoadError> {
+        self.load_history(path, options)
+    }
+}
+
+impl ActivityWatchSource {
+    fn read_export<T: DeserializeOwned>(path: &str) -> Result<T, ExportError> {
+        let file = fs::File::open(path)?;
+        serde_json::from_reader(BufReader::new(file)).map_err(ExportError::InvalidJson)
+    }
+
+    /// Checks that `path` is an export with afk or input buckets, without reading its events
+    pub fn check_export(path: &str) -> Result<(), ExportError> {
+        let summary: ExportSummary = ActivityWatchSource::read_export(path)?;
+        check_bucket_types(summary.buckets.values().map(|bucket| bucket.bucket_type.as_str()))
+    }
+
+    fn convert(export: Export, options: &LoadOptions) -> Result<Loaded, ExportError> {
+        check_bucket_types(export.buckets.values().map(|bucket| bucket.bucket_type.as_str()))?;
+
+        let mut active = vec![];
+        let mut inputs = vec![];
+        let mut warnings: Vec<LoadError> = vec![];
+        for (id, bucket) in export.buckets {
+            for (index, event) in bucket.events.into_iter().enumerate() {
+                let invalid = |error: serde_json::Error| ExportError::InvalidEvent {
+                    bucket: id.clone(),
+                    index,
+                    reason: error.to_string(),
+                };
+                let result = match bucket.bucket_type.as_str() {
+                    AFK_BUCKET_TYPE => serde_json::from_value::<Event<AfkData>>(event).map(|event| {
+                        // Time away only counts towards the day's range if the user was active around it
+                        if event.data.status == NOT_AFK_STATUS {
+                            active.push(event.interval());
+                        }
+                    }),
+                    INPUT_BUCKET_TYPE => serde_json::from_value::<Event<InputData>>(event).map(|event| inputs.push(event)),
+                    _ => Ok(()),
+                };
+                if let Err(error) = result {
+                    warnings.push(invalid(error).into());
+                }
+            }
+        }
+
+        let mut days: BTreeMap<NaiveDate, DayTotals> = BTreeMap::new();
+        // Each machine, or a restarted watcher, has its own afk bucket, so the same time can be active in several
+        for (start, end) in ActivityWatchSource::merge_intervals(active) {
+            for (date, start, end) in ActivityWatchSource::split_at_workdays(options, start, end) {
+                ActivityWatchSource::day_totals(&mut days, date, start, end).active_seconds += seconds_between(start, end);
+            }
+        }
+        for event in inputs {
+            let (start, end) = event.interval();
+            let movement = (event.data.delta_x as f64).hypot(event.data.delta_y as f64);
+            for (i, (date, start, end)) in ActivityWatchSource::split_at_workdays(options, start, end).into_iter().enumerate() {
+                let totals = ActivityWatchSource::day_totals(&mut days, date, start, end);
+                if movement > 0.0 {
+                    totals.movement_seconds += seconds_between(start, end);
+                }
+                // Counts can't be split, they go to the day the event starts on
+                if i == 0 {
+                    totals.movement += movement;
+                    totals.clicks += event.data.clicks;
+                    totals.keystrokes += event.data.presses;
+                }
+            }
+        }
+
+        let days = days.into_iter()
+            .map(|(date, totals)| (date, Day::from_session(totals.into_session())))
+            .collect();
+        Ok(Loaded {
+            history: History {
+                format: None,
+                days,
+            },
+            warnings,
+        })
+    }
+
+    /// Joins intervals that overlap or touch, returning them in start order
+    fn merge_intervals(mut intervals: Vec<(DateTime<Utc>, DateTime<Utc>)>) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+        intervals.sort();
+        let mut merged: Vec<(DateTime<Utc>, DateTime<Utc>)> = vec![];
+        for (start, end) in intervals {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        merged
+    }
+
+    /// Splits an interval where a workday ends, giving each part with the workday it falls on
+    fn split_at_workdays(options: &LoadOptions, mut start: DateTime<Utc>, end: DateTime<Utc>)
+                         -> Vec<(NaiveDate, DateTime<FixedOffset>, DateTime<FixedOffset>)> {
+        let mut parts = vec![];
+        loop {
+            let local_start = options.timezone.localize(start);
+            let date = options.workday(&local_start);
+            let next_day_start = date.succ_opt()
+                .map(|next| options.timezone.resolve(next.and_time(options.day_start)).with_timezone(&Utc))
+                .unwrap_or(end);
+            // A boundary that isn't after the start, e.g. around a timezone change, would never end the loop
+            let part_end = if next_day_start > start { next_day_start.min(end) } else { end };
+            parts.push((date, local_start, options.timezone.localize(part_end)));
+            if part_end >= end {
+                return parts;
+            }
+            start = part_end;
+        }
+    }
+
+    /// The totals for `date`, widened to cover `start` to `end`
+    fn day_totals(days: &mut BTreeMap<NaiveDate, DayTotals>, date: NaiveDate, start: DateTime<FixedOffset>,
+                  end: DateTime<FixedOffset>) -> &mut DayTotals {
+        let totals = days.entry(date).or_insert_with(|| DayTotals::new(start, end));
+        totals.start = totals.start.min(start);
+        totals.end = totals.end.max(end);
+        totals
+    }
+}
+
+impl<D> Event<D> {
+    fn interval(&self) -> (DateTime<Utc>, DateTime<Utc>) {
+        let end = self.timestamp + chrono::Duration::milliseconds((self.duration.max(0.0) * 1000.0) as i64);
+        (self.timestamp, end)
+    }
+}
+
+fn seconds_between(start: DateTime<FixedOffset>, end: DateTime<FixedOffset>) -> f64 {
+    (end - start).num_milliseconds() as f64 / 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXPORT: &str = r#"{"buckets": {
+        "aw-watcher-afk_desktop": {"id": "aw-watcher-afk_desktop", "type": "afkstatus", "hostname": "desktop", "events": [
+            {"id": 3, "timestamp": "2022-11-08T09:00:00+00:00", "duration": 1800.0, "data": {"status": "not-afk"}},
+            {"id": 2, "timestamp": "2022-11-07T10:00:00.000000+00:00", "duration": 600.0, "data": {"status": "afk"}},
+            {"id": 1, "timestamp": "2022-11-07T09:00:00.000000+00:00", "duration": 3600.0, "data": {"status": "not-afk"}},
+            {"id": 0, "timestamp": "yesterday", "duration": 60.0, "data": {"status": "not-afk"}}
+        ]},
+        "aw-watcher-input_desktop": {"id": "aw-watcher-input_desktop", "type": "os.hid.input", "hostname": "desktop", "events": [
+            {"id": 2, "timestamp": "2022-11-07T09:00:01+00:00", "duration": 1.0, "data": {"presses": 0, "clicks": 2, "deltaX": 30, "deltaY": 40, "scrollX": 0, "scrollY": 0}},
+            {"id": 1, "timestamp": "2022-11-07T09:00:00+00:00", "duration": 1.0, "data": {"presses": 12, "clicks": 1, "deltaX": 0, "deltaY": 0, "scrollX": 0, "scrollY": 0}}
+        ]},
+        "aw-watcher-window_desktop": {"id": "aw-watcher-window_desktop", "type": "currentwindow", "events": [
+            {"id": 1, "timestamp": "2022-11-07T09:00:00+00:00", "duration": 5.0, "data": {"app": "editor"}}
+        ]}
+    }}"#;
+
+    fn utc_options() -> LoadOptions {
+        let mut options = LoadOptions::default();
+        options.timezone.default = Some(chrono_tz::UTC);
+        options
+    }
+
+    #[test]
+    fn convert_sums_events_per_workday() {
+        let export: Export = serde_json::from_str(EXPORT).unwrap();
+        let loaded = ActivityWatchSource::convert(export, &utc_options()).unwrap();
+        let date = |day: u32| NaiveDate::from_ymd_opt(2022, 11, day).unwrap();
+
+        let day = loaded.history.get(&date(7)).unwrap();
+        assert_eq!(day.stats, InputStats {
+            total_active_time_seconds: 3600,
+            total_mouse_movement: 50,
+            total_mouse_click_movement: 0,
+            total_mouse_movement_time: 1,
+            total_mouse_clicks: 3,
+            total_keystrokes: 12,
+        });
+        // The afk event after the active hour doesn't extend the day
+        assert_eq!(day.recorded_seconds(), 3600);
+        assert_eq!(loaded.history.get(&date(8)).unwrap().stats.total_active_time_seconds, 1800);
+        assert!(matches!(&loaded.warnings[..], [LoadError::ActivityWatch(ExportError::InvalidEvent { index: 3, .. })]));
+    }
+
+    #[test]
+    fn convert_merges_overlapping_afk_buckets() {
+        let export: Export = serde_json::from_str(r#"{"buckets": {
+            "aw-watcher-afk_desktop": {"type": "afkstatus", "events": [
+                {"timestamp": "2022-11-07T09:00:00+00:00", "duration": 3600.0, "data": {"status": "not-afk"}},
+                {"timestamp": "2022-11-07T10:00:00+00:00", "duration": 7200.0, "data": {"status": "afk"}}
+            ]},
+            "aw-watcher-afk_desktop-2": {"type": "afkstatus", "events": [
+                {"timestamp": "2022-11-07T09:30:00+00:00", "duration": 3600.0, "data": {"status": "not-afk"}}
+            ]}
+        }}"#).unwrap();
+        let loaded = ActivityWatchSource::convert(export, &utc_options()).unwrap();
+
+        let day = loaded.history.get(&NaiveDate::from_ymd_opt(2022, 11, 7).unwrap()).unwrap();
+        assert_eq!(day.stats.total_active_time_seconds, 5400);
+        assert_eq!(day.recorded_seconds(), 5400);
+    }
+
+    #[test]
+    fn convert_splits_events_at_day_start() {
+        let export: Export = serde_json::from_str(r#"{"buckets": {
+            "aw-watcher-afk_desktop": {"type": "afkstatus", "events": [
+                {"timestamp": "2022-11-07T05:00:00+00:00", "duration": 7200.0, "data": {"status": "not-afk"}}
+            ]}
+        }}"#).unwrap();
+        let mut options = utc_options();
+        options.day_start = NaiveTime::from_hms_opt(6, 0, 0).unwrap();
+        let loaded = ActivityWatchSource::convert(export, &options).unwrap();
+
+        let active: Vec<(NaiveDate, u64)> = loaded.history.iter()
+            .map(|(date, day)| (*date, day.stats.total_active_time_seconds))
+            .collect();
+        assert_eq!(active, vec![(NaiveDate::from_ymd_opt(2022, 11, 6).unwrap(), 3600), (NaiveDate::from_ymd_opt(2022, 11, 7).unwrap(), 3600)]);
+    }
+
+    #[test]
+    fn convert_uses_timezone_and_day_start() {
+        let export: Export = serde_json::from_str(EXPORT).unwrap();
+        let mut options = utc_options();
+        options.timezone.default = Some(chrono_tz::America::New_York);
+        options.day_start = NaiveTime::from_hms_opt(6, 0, 0).unwrap();
+        let loaded = ActivityWatchSource::convert(export, &options).unwrap();
+
+        // 09:00 UTC is 04:00 in New York, before the day starts
+        let dates: Vec<NaiveDate> = loaded.history.iter().map(|(date, _)| *date).collect();
+        assert_eq!(dates, vec![NaiveDate::from_ymd_opt(2022, 11, 6).unwrap(), NaiveDate::from_ymd_opt(2022, 11, 7).unwrap()]);
+    }
+
+    #[test]
+    fn check_export_reads_bucket_types() {
+        let path = std::env::temp_dir().join(format!("informant-aw-check-{}.json", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        std::fs::write(&path, EXPORT).unwrap();
+        assert!(ActivityWatchSource::check_export(&path).is_ok());
+
+        std::fs::write(&path, r#"{"buckets": {"aw-watcher-window_desktop": {"type": "currentwindow", "events": [{"id": 1}]}}}"#).unwrap();
+        assert!(matches!(ActivityWatchSource::check_export(&path), Err(ExportError::NoSupportedBuckets)));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn convert_rejects_exports_without_afk_or_input_buckets() {
+        let export: Export = serde_json::from_str(r#"{"buckets": {"aw-watcher-window_desktop": {"type": "currentwindow"}}}"#).unwrap();
+        assert!(matches!(ActivityWatchSource::convert(export, &utc_options()), Err(ExportError::NoSupportedBuckets)));
+    }
+}
@@ -13,20 +13,25 @@ pub struct Informant {
 
 impl Informant {
     fn top_panel_style() -> BTreeMap<TextStyle, FontId>{
-        return [(egui::TextStyle::Button,
-                 egui::FontId::new(24.0, egui::FontFamily::Proportional))]
+        [(egui::TextStyle::Button,
+          egui::FontId::new(24.0, egui::FontFamily::Proportional))]
             .into()
     }
 }
 
 impl Default for Informant {
     fn default() -> Self {
+        let settings_tab = settings::SettingsTab::default();
+        // Open on the Settings tab so a problem with the settings file is seen
+        let settings = &settings_tab.settings;
+        let current_tab = match settings.load_warning.is_some() || settings.save_error.is_some() {
+            true => Tab::Settings,
+            false => Tab::Stats,
+        };
         Self {
-            current_tab: Tab::Stats,
+            current_tab,
             stats_tab: stats::StatsTab::default(),
-            settings_tab: settings::SettingsTab {
-                settings: settings::Settings::default()
-            },
+            settings_tab,
         }
     }
 }
@@ -45,18 +50,24 @@ impl eframe::App for Informant {
 
                 ui.selectable_value(&mut self.current_tab, Tab::Stats, "Stats");
                 ui.selectable_value(&mut self.current_tab, Tab::Settings, "Settings");
+
+                ui.with_layout(Layout::right_to_left(Align::Center), |ui| {
+                    if self.settings_tab.profile_switcher_ui(ui) {
+                        self.stats_tab.request_reload();
+                    }
+                    ui.label("Profile");
+                });
             });
         });
         egui::CentralPanel::default().show(ctx, |ui| {
             match self.current_tab {
                 Tab::Stats => {
-                    self.stats_tab.ui(ui,
-                                      &self.settings_tab.settings.workrave_historystats_path,
-                                      &self.settings_tab.settings.workrave_todaystats_path,
-                                      &frame);
+                    self.stats_tab.ui(ui, &self.settings_tab.settings, frame);
                 }
                 Tab::Settings => {
-                    self.settings_tab.ui(ui);
+                    if self.settings_tab.ui(ui) {
+                        self.stats_tab.request_reload();
+                    }
                 }
             }
         });
@@ -0,0 +1,266 @@
+use crate::history::{History, LoadOptions};
+use crate::workrave::{LoadedHistory, ParseError, ParsePosition};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+/// Bumped whenever `CacheEntry` or the types it contains change shape
+const CACHE_VERSION: u32 = 4;
+/// Numbers the temporary files entries are written to, so concurrent writes don't share one
+static NEXT_TEMP_FILE: AtomicU64 = AtomicU64::new(0);
+/// How many bytes before the end of the parsed content are compared to spot a rewritten file
+const CHECK_BYTES: u64 = 256;
+
+/// Parsed historystats files stored on disk, so launching doesn't parse the whole file again
+#[derive(Clone)]
+pub struct HistoryCache {
+    dir: PathBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+    version: u32,
+    path: String,
+    options: LoadOptions,
+    modified: SystemTime,
+    /// Where parsing stopped, appended sessions are parsed from here
+    position: ParsePosition,
+    /// The bytes just before `position`, used to check the file was only appended to
+    check_bytes: Vec<u8>,
+    history: History,
+    warnings: Vec<ParseError>,
+}
+
+impl HistoryCache {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// A cache in the user's cache directory, if this platform has one
+    pub fn in_user_cache_dir() -> Option<Self> {
+        dirs::cache_dir().map(|dir| HistoryCache::new(dir.join("informant")))
+    }
+
+    /// Loads a historystats file, reusing the cached parse when the file is unchanged and only
+    /// parsing the new sessions when it has been appended to
+    pub fn load_historystats(&self, path: &str, options: &LoadOptions) -> Result<LoadedHistory, ParseError> {
+        let mut file = fs::File::open(path)?;
+        let metadata = file.metadata()?;
+        let modified = metadata.modified()?;
+        let size = metadata.len();
+
+        let entry_path = self.entry_path(path);
+        let cached = HistoryCache::read_entry(&entry_path)
+            .filter(|entry| entry.version == CACHE_VERSION && entry.path == path && entry.options == *options);
+
+        let mut cached_position = None;
+        let mut loaded = match cached {
+            Some(entry) if entry.modified == modified && entry.position.bytes == size => {
+                return Ok(entry.into_loaded());
+            }
+            Some(entry) if size > entry.position.bytes && entry.is_prefix_of(&mut file)? => {
+                let position = entry.position;
+                cached_position = Some(position);
+                let mut loaded = entry.into_loaded();
+                file.seek(SeekFrom::Start(position.bytes))?;
+                let (warnings, resume_at, unfinished_warnings) = loaded.history.parse_sessions(BufReader::new(&file), options, position)?;
+                loaded.warnings.extend(warnings);
+                loaded.resume_at = resume_at;
+                loaded.unfinished_warnings = unfinished_warnings;
+                loaded
+            }
+            _ => {
+                file.rewind()?;
+                History::load_historystats_file(&file, options)?
+            }
+        };
+
+        // Only the complete sessions are cached, anything after them is parsed again on the next load
+        let position = match loaded.resume_at {
+            Some(position) if Some(position) != cached_position => position,
+            _ => return Ok(loaded),
+        };
+        let unfinished = loaded.warnings.split_off(loaded.warnings.len() - loaded.unfinished_warnings);
+        let entry = CacheEntry {
+            version: CACHE_VERSION,
+            path: path.to_string(),
+            options: options.clone(),
+            modified,
+            position,
+            check_bytes: read_check_bytes(&mut file, position.bytes)?,
+            history: loaded.history,
+            warnings: loaded.warnings,
+        };
+        if let Err(error) = self.write_entry(&entry_path, &entry) {
+            println!("Failed to cache {}: {}", path, error);
+            let _ = fs::remove_file(&entry_path);
+        }
+        let mut loaded = entry.into_loaded();
+        loaded.unfinished_warnings = unfinished.len();
+        loaded.warnings.extend(unfinished);
+        Ok(loaded)
+    }
+
+    fn entry_path(&self, path: &str) -> PathBuf {
+        let mut hasher = DefaultHasher::new();
+        path.hash(&mut hasher);
+        self.dir.join(format!("historystats-{:016x}.bin", hasher.finish()))
+    }
+
+    fn read_entry(entry_path: &Path) -> Option<CacheEntry> {
+        let file = fs::File::open(entry_path).ok()?;
+        bincode::deserialize_from(BufReader::new(file)).ok()
+    }
+
+    /// Writes the entry to a temporary file then renames it over `entry_path`, so loads running at the
+    /// same time each replace the entry whole instead of interleaving their writes
+    fn write_entry(&self, entry_path: &Path, entry: &CacheEntry) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let temp_path = entry_path.with_extension(format!("{}-{}.tmp", std::process::id(),
+                                                          NEXT_TEMP_FILE.fetch_add(1, Ordering::Relaxed)));
+        let result = fs::File::create(&temp_path)
+            .and_then(|file| {
+                let mut writer = BufWriter::new(file);
+                bincode::serialize_into(&mut writer, entry).map_err(io::Error::other)?;
+                writer.flush()
+            })
+            .and_then(|_| fs::rename(&temp_path, entry_path));
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        result
+    }
+}
+
+impl CacheEntry {
+    fn into_loaded(self) -> LoadedHistory {
+        LoadedHistory {
+            history: self.history,
+            warnings: self.warnings,
+            resume_at: Some(self.position),
+            unfinished_warnings: 0,
+        }
+    }
+
+    /// Whether `file` still has the content this entry was parsed from at its start
+    fn is_prefix_of(&self, file: &mut fs::File) -> io::Result<bool> {
+        Ok(read_check_bytes(file, self.position.bytes)? == self.check_bytes)
+    }
+}
+
+fn read_check_bytes(file: &mut fs::File, end: u64) -> io::Result<Vec<u8>> {
+    let start = end.saturating_sub(CHECK_BYTES);
+    let mut bytes = vec![0; (end - start) as usize];
+    file.seek(SeekFrom::Start(start))?;
+    file.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HISTORY: &str = "WorkRaveStats 4\n\
+                           D 7 10 122 9 0 7 10 122 17 0\n\
+                           B 0 7 10 9 8 1 0 0 0 \n\
+                           m 6 3600 100 20 50 30 500 \n";
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("informant-cache-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn history_string(history: &History) -> String {
+        let mut written = vec![];
+        history.write_historystats(&mut written).unwrap();
+        String::from_utf8(written).unwrap()
+    }
+
+    #[test]
+    fn test_cache_hit() {
+        let dir = test_dir("hit");
+        let path = dir.join("historystats").to_string_lossy().to_string();
+        fs::write(&path, HISTORY).unwrap();
+        let cache = HistoryCache::new(dir.join("cache"));
+        let options = LoadOptions::default();
+
+        cache.load_historystats(&path, &options).unwrap();
+        let entry = HistoryCache::read_entry(&cache.entry_path(&path)).unwrap();
+        assert_eq!(entry.position, ParsePosition { bytes: HISTORY.len() as u64, line: 4 });
+        // Only the entry is left behind, not the temporary file it was written to
+        assert_eq!(fs::read_dir(dir.join("cache")).unwrap().count(), 1);
+
+        let loaded = cache.load_historystats(&path, &options).unwrap();
+        assert_eq!(history_string(&loaded.history), HISTORY);
+    }
+
+    #[test]
+    fn test_cache_parses_appended_sessions() {
+        let dir = test_dir("append");
+        let path = dir.join("historystats").to_string_lossy().to_string();
+        fs::write(&path, HISTORY).unwrap();
+        let cache = HistoryCache::new(dir.join("cache"));
+        let options = LoadOptions::default();
+        cache.load_historystats(&path, &options).unwrap();
+
+        let appended = "D 8 10 122 9 0 8 10 122 17 0\n\
+                        m 6 1800 100 20 50 30 500 \n\
+                        bad line\n";
+        fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(appended.as_bytes()).unwrap();
+
+        let loaded = cache.load_historystats(&path, &options).unwrap();
+        let full = History::load_historystats(&path, &options).unwrap();
+        assert_eq!(loaded.history.days.len(), 2);
+        assert_eq!(history_string(&loaded.history), history_string(&full.history));
+        assert!(matches!(loaded.warnings[..], [ParseError::UnexpectedLine { line: 7, .. }]));
+        assert_eq!(loaded.resume_at, full.resume_at);
+    }
+
+    #[test]
+    fn test_cache_reparses_rewritten_file() {
+        let dir = test_dir("rewrite");
+        let path = dir.join("historystats").to_string_lossy().to_string();
+        fs::write(&path, HISTORY).unwrap();
+        let cache = HistoryCache::new(dir.join("cache"));
+        let options = LoadOptions::default();
+        cache.load_historystats(&path, &options).unwrap();
+
+        let rewritten = HISTORY.replace("m 6 3600", "m 6 7200") + "D 8 10 122 9 0 8 10 122 17 0\n";
+        fs::write(&path, rewritten).unwrap();
+
+        let loaded = cache.load_historystats(&path, &options).unwrap();
+        let day = loaded.history.days.values().next().unwrap();
+        assert_eq!(day.stats.total_active_time_seconds, 7200);
+        assert_eq!(loaded.resume_at, Some(ParsePosition { bytes: HISTORY.len() as u64, line: 4 }));
+        assert_eq!(loaded.unfinished_warnings, 1);
+    }
+
+    #[test]
+    fn test_cache_keeps_sessions_before_an_unfinished_day() {
+        let dir = test_dir("unfinished");
+        let path = dir.join("historystats").to_string_lossy().to_string();
+        fs::write(&path, HISTORY.to_string() + "D 8 10 122 9 0 8 10 122 17 0\n").unwrap();
+        let cache = HistoryCache::new(dir.join("cache"));
+        let options = LoadOptions::default();
+
+        let loaded = cache.load_historystats(&path, &options).unwrap();
+        assert!(matches!(loaded.warnings[..], [ParseError::IncompleteBlock { line: 5 }]));
+        let entry = HistoryCache::read_entry(&cache.entry_path(&path)).unwrap();
+        assert_eq!(entry.position, ParsePosition { bytes: HISTORY.len() as u64, line: 4 });
+        assert!(entry.warnings.is_empty());
+
+        // The unfinished day is parsed again from the cached position, without repeating its warning
+        let loaded = cache.load_historystats(&path, &options).unwrap();
+        assert_eq!(loaded.history.days.len(), 1);
+        assert!(matches!(loaded.warnings[..], [ParseError::IncompleteBlock { line: 5 }]));
+    }
+}
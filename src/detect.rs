@@ -0,0 +1,186 @@
+use crate::history;
+use std::path::{Path, PathBuf};
+
+const FLATPAK_APP_DIR: &str = ".var/app/org.workrave.Workrave";
+
+/// A path that was checked for a Workrave stats file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub path: String,
+    pub status: CandidateStatus,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CandidateStatus {
+    Valid,
+    Invalid,
+    Missing,
+}
+
+impl CandidateStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CandidateStatus::Valid => "found",
+            CandidateStatus::Invalid => "not a valid stats file",
+            CandidateStatus::Missing => "not found",
+        }
+    }
+}
+
+/// The result of looking for one stats file in every place Workrave might keep it
+#[derive(Debug, Clone, Default)]
+pub struct Detection {
+    pub found: Option<String>,
+    pub candidates: Vec<Candidate>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Platform {
+    Windows,
+    MacOs,
+    Linux,
+}
+
+impl Platform {
+    fn current() -> Platform {
+        if cfg!(windows) {
+            Platform::Windows
+        } else if cfg!(target_os = "macos") {
+            Platform::MacOs
+        } else {
+            Platform::Linux
+        }
+    }
+}
+
+/// The user directories Workrave's own locations are based on
+struct BaseDirs {
+    home: Option<PathBuf>,
+    config: Option<PathBuf>,
+    data: Option<PathBuf>,
+}
+
+impl BaseDirs {
+    fn current() -> BaseDirs {
+        BaseDirs {
+            home: dirs::home_dir(),
+            config: dirs::config_dir(),
+            data: dirs::data_dir(),
+        }
+    }
+}
+
+/// Looks for `filename` in each of Workrave's default directories on this platform, in order
+pub fn detect(filename: &str) -> Detection {
+    detect_in(&candidate_directories(Platform::current(), &BaseDirs::current()), filename)
+}
+
+fn detect_in(directories: &[PathBuf], filename: &str) -> Detection {
+    let mut detection = Detection::default();
+    for directory in directories {
+        let path = directory.join(filename).display().to_string();
+        let status = if !Path::new(&path).is_file() {
+            CandidateStatus::Missing
+        } else if history::History::is_file_valid(&path) {
+            CandidateStatus::Valid
+        } else {
+            CandidateStatus::Invalid
+        };
+        if status == CandidateStatus::Valid && detection.found.is_none() {
+            detection.found = Some(path.clone());
+        }
+        detection.candidates.push(Candidate { path, status });
+    }
+    detection
+}
+
+fn candidate_directories(platform: Platform, base: &BaseDirs) -> Vec<PathBuf> {
+    let home = |path: &str| base.home.as_ref().map(|home| home.join(path));
+    let directories = match platform {
+        Platform::Windows => vec![
+            base.config.as_ref().map(|config| config.join("Workrave")),
+            Some(PathBuf::from(format!("C:\\Users\\{}\\AppData\\Roaming\\Workrave", whoami::username()))),
+        ],
+        Platform::MacOs => vec![
+            home("Library/Application Support/Workrave"),
+            home(".workrave"),
+        ],
+        Platform::Linux => vec![
+            home(".workrave"),
+            base.config.as_ref().map(|config| config.join("workrave")),
+            base.data.as_ref().map(|data| data.join("workrave")),
+            home(&format!("{}/.workrave", FLATPAK_APP_DIR)),
+            home(&format!("{}/config/workrave", FLATPAK_APP_DIR)),
+            home(&format!("{}/data/workrave", FLATPAK_APP_DIR)),
+        ],
+    };
+
+    let mut unique: Vec<PathBuf> = vec![];
+    for directory in directories.into_iter().flatten() {
+        if !unique.contains(&directory) {
+            unique.push(directory);
+        }
+    }
+    unique
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn linux_dirs(home: &Path) -> BaseDirs {
+        BaseDirs {
+            home: Some(home.to_path_buf()),
+            config: Some(home.join(".config")),
+            data: Some(home.join(".local/share")),
+        }
+    }
+
+    #[test]
+    fn candidate_directories_linux() {
+        let home = Path::new("/home/user");
+        let directories = candidate_directories(Platform::Linux, &linux_dirs(home));
+
+        assert_eq!(directories, vec![
+            home.join(".workrave"),
+            home.join(".config/workrave"),
+            home.join(".local/share/workrave"),
+            home.join(".var/app/org.workrave.Workrave/.workrave"),
+            home.join(".var/app/org.workrave.Workrave/config/workrave"),
+            home.join(".var/app/org.workrave.Workrave/data/workrave"),
+        ]);
+    }
+
+    #[test]
+    fn candidate_directories_without_home() {
+        let base = BaseDirs { home: None, config: None, data: None };
+
+        assert!(candidate_directories(Platform::MacOs, &base).is_empty());
+        assert!(candidate_directories(Platform::Linux, &base).is_empty());
+    }
+
+    #[test]
+    fn detect_skips_invalid_files() {
+        let home = std::env::temp_dir().join(format!("informant-detect-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&home);
+        let directories = candidate_directories(Platform::Linux, &linux_dirs(&home));
+        fs::create_dir_all(&directories[0]).unwrap();
+        fs::write(directories[0].join("historystats"), "not stats\n").unwrap();
+        fs::create_dir_all(&directories[3]).unwrap();
+        fs::write(directories[3].join("historystats"), "WorkRaveStats 4\n").unwrap();
+
+        let detection = detect_in(&directories, "historystats");
+        let statuses: Vec<CandidateStatus> = detection.candidates.iter().map(|c| c.status).collect();
+
+        assert_eq!(detection.found, Some(directories[3].join("historystats").display().to_string()));
+        assert_eq!(statuses, vec![CandidateStatus::Invalid,
+                                  CandidateStatus::Missing,
+                                  CandidateStatus::Missing,
+                                  CandidateStatus::Valid,
+                                  CandidateStatus::Missing,
+                                  CandidateStatus::Missing]);
+        fs::remove_dir_all(&home).unwrap();
+    }
+}
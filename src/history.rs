@@ -0,0 +1,670 @@
+use crate::workrave::StatsFormat;
+use chrono::prelude::*;
+use chrono::{Days, Duration, LocalResult, Months};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::collections::btree_map::Entry;
+use std::ops::{AddAssign, RangeBounds};
+
+/// A continuous stretch of recorded activity, e.g. one "D" block of Workrave's stats
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Session {
+    pub datetime_range: DatetimeRange,
+    pub stats: InputStats,
+    pub breaks: BTreeMap<BreakType, BreakStats>,
+    /// Name of the machine or profile the session was loaded from, `None` until one is set
+    #[serde(default)]
+    pub source: Option<String>,
+}
+
+/// Every session recorded on a calendar day, with their stats summed together
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Day {
+    pub datetime_range: DatetimeRange,
+    pub stats: InputStats,
+    pub breaks: BTreeMap<BreakType, BreakStats>,
+    pub sessions: Vec<Session>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Copy, Clone)]
+pub struct DatetimeRange {
+    start: DateTime<FixedOffset>,
+    end: DateTime<FixedOffset>,
+}
+
+impl DatetimeRange {
+    pub fn new(start: DateTime<FixedOffset>, end: DateTime<FixedOffset>) -> DatetimeRange {
+        DatetimeRange { start, end }
+    }
+
+    pub fn start(&self) -> DateTime<FixedOffset> {
+        self.start
+    }
+
+    pub fn end(&self) -> DateTime<FixedOffset> {
+        self.end
+    }
+
+    /// Seconds between the start and end, zero if the end is before the start
+    pub fn duration_seconds(&self) -> u64 {
+        (self.end - self.start).num_seconds().max(0) as u64
+    }
+}
+
+/// Input intensity independent of how long the day was
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
+pub struct ActivityRates {
+    pub keystrokes_per_active_minute: f64,
+    pub clicks_per_active_hour: f64,
+    /// Movement units per second of mouse movement time
+    pub mouse_speed: f64,
+    /// Share of the recorded time that was not active, from 0.0 to 1.0
+    pub idle_ratio: f64,
+}
+
+impl ActivityRates {
+    pub fn new(stats: &InputStats, recorded_seconds: u64) -> ActivityRates {
+        let per = |value: u64, seconds: u64, scale: f64| match seconds {
+            0 => 0.0,
+            _ => value as f64 / (seconds as f64 / scale),
+        };
+        let active_seconds = stats.total_active_time_seconds;
+        ActivityRates {
+            keystrokes_per_active_minute: per(stats.total_keystrokes, active_seconds, 60.0),
+            clicks_per_active_hour: per(stats.total_mouse_clicks, active_seconds, 3600.0),
+            mouse_speed: per(stats.total_mouse_movement, stats.total_mouse_movement_time, 1.0),
+            idle_ratio: match recorded_seconds {
+                0 => 0.0,
+                _ => 1.0 - (active_seconds as f64 / recorded_seconds as f64).min(1.0),
+            },
+        }
+    }
+}
+
+/// Uses a different timezone for the sessions recorded between two dates, e.g. while travelling
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct TimezoneOverride {
+    pub start: NaiveDate,
+    pub end: NaiveDate,
+    pub timezone: Tz,
+}
+
+/// The timezone the stats were recorded in, `None` uses this machine's timezone
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+pub struct TimezoneConfig {
+    pub default: Option<Tz>,
+    pub overrides: Vec<TimezoneOverride>,
+}
+
+impl TimezoneConfig {
+    /// Resolves a wall clock time from a stats file to a fixed offset.
+    /// Ambiguous times use the earlier of the two offsets, and times skipped by a DST transition
+    /// use the offset from before the transition, so 01:30 in a 01:00-02:00 gap becomes 02:30.
+    pub fn resolve(&self, datetime: NaiveDateTime) -> DateTime<FixedOffset> {
+        match self.timezone_on(datetime.date()) {
+            Some(tz) => TimezoneConfig::resolve_in(&tz, datetime),
+            None => TimezoneConfig::resolve_in(&Local, datetime),
+        }
+    }
+
+    /// Converts an instant to the local time it was recorded at, for sources that store UTC times
+    pub fn localize(&self, datetime: DateTime<Utc>) -> DateTime<FixedOffset> {
+        // Override dates are local to the override's own timezone
+        let overridden = self.overrides.iter().find(|o| {
+            let date = datetime.with_timezone(&o.timezone).date_naive();
+            o.start <= date && date <= o.end
+        });
+        match overridden.map(|o| Some(o.timezone)).unwrap_or(self.default) {
+            Some(tz) => datetime.with_timezone(&tz).fixed_offset(),
+            None => datetime.with_timezone(&Local).fixed_offset(),
+        }
+    }
+
+    fn timezone_on(&self, date: NaiveDate) -> Option<Tz> {
+        self.overrides.iter()
+            .find(|o| o.start <= date && date <= o.end)
+            .map(|o| Some(o.timezone))
+            .unwrap_or(self.default)
+    }
+
+    fn resolve_in<T: TimeZone>(timezone: &T, datetime: NaiveDateTime) -> DateTime<FixedOffset> {
+        match timezone.from_local_datetime(&datetime) {
+            LocalResult::Single(resolved) => resolved.fixed_offset(),
+            LocalResult::Ambiguous(earliest, _) => earliest.fixed_offset(),
+            LocalResult::None => {
+                let offset_before = timezone.offset_from_utc_datetime(&(datetime - Duration::days(1))).fix();
+                let utc = datetime - Duration::seconds(offset_before.local_minus_utc() as i64);
+                timezone.from_utc_datetime(&utc).fixed_offset()
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+pub struct LoadOptions {
+    pub timezone: TimezoneConfig,
+    /// Sessions starting before this time of day count towards the previous workday
+    pub day_start: NaiveTime,
+}
+
+impl LoadOptions {
+    /// The workday a session belongs to, based on its local start time and `day_start`
+    pub fn workday(&self, start: &DateTime<FixedOffset>) -> NaiveDate {
+        let day_start_offset = self.day_start - NaiveTime::MIN;
+        (start.naive_local() - day_start_offset).date()
+    }
+
+    /// The workday it currently is in the timezone the stats are recorded in, including any override
+    pub fn today(&self) -> NaiveDate {
+        self.today_at(Utc::now())
+    }
+
+    fn today_at(&self, now: DateTime<Utc>) -> NaiveDate {
+        self.workday(&self.timezone.localize(now))
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Copy, Clone, Default)]
+pub struct InputStats {
+    pub total_active_time_seconds: u64,
+    /// Movement in Workrave's units (screen pixels), see `MovementCalibration` to convert to meters
+    pub total_mouse_movement: u64,
+    pub total_mouse_click_movement: u64,
+    pub total_mouse_movement_time: u64,
+    pub total_mouse_clicks: u64,
+    pub total_keystrokes: u64,
+}
+
+impl InputStats {
+    pub fn value(&self, field: StatsField) -> u64 {
+        match field {
+            StatsField::ActiveTime => self.total_active_time_seconds,
+            StatsField::MouseMovement => self.total_mouse_movement,
+            StatsField::ClickMovement => self.total_mouse_click_movement,
+            StatsField::MovementTime => self.total_mouse_movement_time,
+            StatsField::Clicks => self.total_mouse_clicks,
+            StatsField::Keystrokes => self.total_keystrokes,
+        }
+    }
+}
+
+impl AddAssign for InputStats {
+    fn add_assign(&mut self, other: Self) {
+        self.total_active_time_seconds += other.total_active_time_seconds;
+        self.total_mouse_movement += other.total_mouse_movement;
+        self.total_mouse_click_movement += other.total_mouse_click_movement;
+        self.total_mouse_movement_time += other.total_mouse_movement_time;
+        self.total_mouse_clicks += other.total_mouse_clicks;
+        self.total_keystrokes += other.total_keystrokes;
+    }
+}
+
+/// Converts Workrave's movement units, which are screen pixels, to meters for display
+#[derive(Serialize, Deserialize, PartialEq, Debug, Copy, Clone)]
+pub struct MovementCalibration {
+    pub pixels_per_meter: f64,
+}
+
+impl Default for MovementCalibration {
+    fn default() -> Self {
+        Self {
+            pixels_per_meter: 4288.0,
+        }
+    }
+}
+
+impl MovementCalibration {
+    const METERS_PER_INCH: f64 = 0.0254;
+
+    /// Builds a calibration from a monitor's diagonal size and resolution
+    pub fn from_monitor(diagonal_inches: f64, width_pixels: u32, height_pixels: u32) -> Option<MovementCalibration> {
+        if diagonal_inches <= 0.0 || width_pixels == 0 || height_pixels == 0 {
+            return None;
+        }
+        let diagonal_pixels = (width_pixels as f64).hypot(height_pixels as f64);
+        Some(MovementCalibration {
+            pixels_per_meter: diagonal_pixels / (diagonal_inches * MovementCalibration::METERS_PER_INCH),
+        })
+    }
+
+    pub fn meters(&self, movement: f64) -> f64 {
+        movement / self.pixels_per_meter
+    }
+
+    /// Converts `movement` measured with this calibration to the pixels `to` counts for the same distance
+    pub fn convert(&self, movement: u64, to: &MovementCalibration) -> u64 {
+        (movement as f64 * to.pixels_per_meter / self.pixels_per_meter).round() as u64
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Copy, Clone)]
+pub enum BreakType {
+    MicroBreak,
+    RestBreak,
+    DailyLimit,
+}
+
+impl BreakType {
+    /// Workrave identifies break types by index on "B" lines
+    pub fn from_id(id: u64) -> Option<BreakType> {
+        match id {
+            0 => Some(BreakType::MicroBreak),
+            1 => Some(BreakType::RestBreak),
+            2 => Some(BreakType::DailyLimit),
+            _ => None,
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        match self {
+            BreakType::MicroBreak => 0,
+            BreakType::RestBreak => 1,
+            BreakType::DailyLimit => 2,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BreakType::MicroBreak => "Micro-break",
+            BreakType::RestBreak => "Rest break",
+            BreakType::DailyLimit => "Daily limit",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Copy, Clone, Default)]
+pub struct BreakStats {
+    pub prompted: u64,
+    pub taken: u64,
+    pub natural_taken: u64,
+    pub skipped: u64,
+    pub postponed: u64,
+    pub unique_breaks: u64,
+    pub total_overdue_seconds: u64,
+}
+
+/// A value in `InputStats`, also used to describe the "m" line layout of each format version
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum StatsField {
+    ActiveTime,
+    MouseMovement,
+    ClickMovement,
+    MovementTime,
+    Clicks,
+    Keystrokes,
+}
+
+impl AddAssign for BreakStats {
+    fn add_assign(&mut self, other: Self) {
+        self.prompted += other.prompted;
+        self.taken += other.taken;
+        self.natural_taken += other.natural_taken;
+        self.skipped += other.skipped;
+        self.postponed += other.postponed;
+        self.unique_breaks += other.unique_breaks;
+        self.total_overdue_seconds += other.total_overdue_seconds;
+    }
+}
+
+impl Day {
+    /// Seconds recorded this day, gaps between sessions are not counted
+    pub fn recorded_seconds(&self) -> u64 {
+        self.sessions.iter().map(|session| session.datetime_range.duration_seconds()).sum()
+    }
+
+    pub fn rates(&self) -> ActivityRates {
+        ActivityRates::new(&self.stats, self.recorded_seconds())
+    }
+
+    pub fn from_session(session: Session) -> Day {
+        Day {
+            datetime_range: session.datetime_range,
+            stats: session.stats,
+            breaks: session.breaks.clone(),
+            sessions: vec![session],
+        }
+    }
+
+    /// A day made of `sessions`, `None` if there are none
+    pub fn from_sessions(sessions: Vec<Session>) -> Option<Day> {
+        let mut sessions = sessions.into_iter();
+        let mut day = Day::from_session(sessions.next()?);
+        for session in sessions {
+            day.add_session(session);
+        }
+        Some(day)
+    }
+
+    /// The part of this day recorded by `source`, `None` if it recorded nothing
+    pub fn for_source(&self, source: &str) -> Option<Day> {
+        Day::from_sessions(self.sessions.iter()
+            .filter(|session| session.source.as_deref() == Some(source))
+            .cloned()
+            .collect())
+    }
+
+    /// Adds another session recorded on this day, keeping the sessions in start order
+    pub fn add_session(&mut self, session: Session) {
+        self.datetime_range.start = self.datetime_range.start.min(session.datetime_range.start);
+        self.datetime_range.end = self.datetime_range.end.max(session.datetime_range.end);
+        self.stats += session.stats;
+        for (break_type, break_stats) in &session.breaks {
+            *self.breaks.entry(*break_type).or_default() += *break_stats;
+        }
+
+        let index = self.sessions.partition_point(|s| s.datetime_range.start <= session.datetime_range.start);
+        self.sessions.insert(index, session);
+    }
+}
+
+/// The length of the periods days are grouped into
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Copy, Clone)]
+pub enum Granularity {
+    Day,
+    Week,
+    Month,
+    Year,
+}
+
+impl Granularity {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Granularity::Day => "Day",
+            Granularity::Week => "Week",
+            Granularity::Month => "Month",
+            Granularity::Year => "Year",
+        }
+    }
+
+    /// The first date of the period containing `date`
+    pub fn period_start(&self, date: NaiveDate, week_start: Weekday) -> NaiveDate {
+        match self {
+            Granularity::Day => date,
+            Granularity::Week => {
+                let days_since_start = date.weekday().days_since(week_start);
+                date - Duration::days(days_since_start as i64)
+            }
+            Granularity::Month => date.with_day(1).unwrap_or(date),
+            Granularity::Year => NaiveDate::from_ymd_opt(date.year(), 1, 1).unwrap_or(date),
+        }
+    }
+
+    /// The last date of the period starting on `start`
+    pub fn period_end(&self, start: NaiveDate) -> NaiveDate {
+        match self {
+            Granularity::Day => start,
+            Granularity::Week => start + Duration::days(6),
+            Granularity::Month => {
+                let next_month = start.checked_add_months(Months::new(1)).unwrap_or(start);
+                next_month.pred_opt().unwrap_or(start)
+            }
+            Granularity::Year => NaiveDate::from_ymd_opt(start.year(), 12, 31).unwrap_or(start),
+        }
+    }
+}
+
+/// How days without any recorded data are treated when averaging
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum GapPolicy {
+    Exclude,
+    CountAsZero,
+}
+
+/// The days recorded within one week, month or year
+#[derive(Debug)]
+pub struct Rollup {
+    pub start: NaiveDate,
+    pub end: NaiveDate,
+    pub day_count: usize,
+    /// Days in this period with no data, between the first and last recorded day of the history
+    pub missing_days: usize,
+    pub sum: InputStats,
+    pub recorded_seconds: u64,
+    daily: Vec<InputStats>,
+}
+
+impl Rollup {
+    fn new(start: NaiveDate, end: NaiveDate) -> Rollup {
+        Rollup {
+            start,
+            end,
+            day_count: 0,
+            missing_days: 0,
+            sum: InputStats::default(),
+            recorded_seconds: 0,
+            daily: vec![],
+        }
+    }
+
+    fn add_day(&mut self, day: &Day) {
+        self.day_count += 1;
+        self.sum += day.stats;
+        self.recorded_seconds += day.recorded_seconds();
+        self.daily.push(day.stats);
+    }
+
+    /// Rates over the whole period, so busier days weigh more than quiet ones
+    pub fn rates(&self) -> ActivityRates {
+        ActivityRates::new(&self.sum, self.recorded_seconds)
+    }
+
+    fn counted_days(&self, gaps: GapPolicy) -> usize {
+        match gaps {
+            GapPolicy::Exclude => self.day_count,
+            GapPolicy::CountAsZero => self.day_count + self.missing_days,
+        }
+    }
+
+    /// Mean per day
+    pub fn mean(&self, field: StatsField, gaps: GapPolicy) -> f64 {
+        match self.counted_days(gaps) {
+            0 => 0.0,
+            days => self.sum.value(field) as f64 / days as f64,
+        }
+    }
+
+    /// Median per day
+    pub fn median(&self, field: StatsField, gaps: GapPolicy) -> f64 {
+        let mut values: Vec<u64> = self.daily.iter().map(|stats| stats.value(field)).collect();
+        values.resize(self.counted_days(gaps), 0);
+        values.sort_unstable();
+        let middle = values.len() / 2;
+        match values.len() {
+            0 => 0.0,
+            n if n % 2 == 0 => (values[middle - 1] + values[middle]) as f64 / 2.0,
+            _ => values[middle] as f64,
+        }
+    }
+}
+
+/// Recorded days by workday date, whichever tracker the data came from
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct History {
+    /// The layout of the historystats file this was loaded from,
+    /// `None` when it was built some other way, e.g. from another tracker's data
+    pub format: Option<StatsFormat>,
+    pub days: BTreeMap<NaiveDate, Day>,
+}
+
+impl History {
+    /// Days in date order
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&NaiveDate, &Day)> {
+        self.days.iter()
+    }
+
+    /// Days within `range` in date order, e.g. `history.range(start..end)`
+    pub fn range<R: RangeBounds<NaiveDate>>(&self, range: R) -> impl DoubleEndedIterator<Item = (&NaiveDate, &Day)> {
+        self.days.range(range)
+    }
+
+    pub fn get(&self, date: &NaiveDate) -> Option<&Day> {
+        self.days.get(date)
+    }
+
+    pub fn first_day(&self) -> Option<(&NaiveDate, &Day)> {
+        self.days.iter().next()
+    }
+
+    pub fn last_day(&self) -> Option<(&NaiveDate, &Day)> {
+        self.days.iter().next_back()
+    }
+
+    /// Every date between the first and last recorded day that has no data
+    pub fn missing_dates(&self) -> Vec<NaiveDate> {
+        let (first, last) = match (self.first_day(), self.last_day()) {
+            (Some((first, _)), Some((last, _))) => (*first, *last),
+            _ => return vec![],
+        };
+        first.iter_days()
+            .take_while(|date| *date <= last)
+            .filter(|date| !self.days.contains_key(date))
+            .collect()
+    }
+
+    /// Groups every date from the first to the last recorded day into periods, in date order.
+    /// Periods that only contain missing dates are still included, with a `day_count` of zero.
+    pub fn rollup(&self, granularity: Granularity, week_start: Weekday) -> Vec<Rollup> {
+        self.rollup_days(granularity, week_start, |day| Some(Cow::Borrowed(day)))
+    }
+
+    /// Like `rollup`, but only counting the sessions recorded by `source`, so the periods line up
+    /// with the combined ones. Days that source recorded nothing on count as missing.
+    pub fn source_rollup(&self, granularity: Granularity, week_start: Weekday, source: &str) -> Vec<Rollup> {
+        self.rollup_days(granularity, week_start, |day| day.for_source(source).map(Cow::Owned))
+    }
+
+    fn rollup_days<F>(&self, granularity: Granularity, week_start: Weekday, select: F) -> Vec<Rollup>
+    where
+        F: Fn(&Day) -> Option<Cow<Day>>,
+    {
+        let (first, last) = match (self.first_day(), self.last_day()) {
+            (Some((first, _)), Some((last, _))) => (*first, *last),
+            _ => return vec![],
+        };
+
+        let mut rollups: Vec<Rollup> = vec![];
+        for date in first.iter_days().take_while(|date| *date <= last) {
+            let start = granularity.period_start(date, week_start);
+            if rollups.last().map(|rollup| rollup.start) != Some(start) {
+                rollups.push(Rollup::new(start, granularity.period_end(start)));
+            }
+            if let Some(rollup) = rollups.last_mut() {
+                match self.days.get(&date).and_then(&select) {
+                    Some(day) => rollup.add_day(&day),
+                    None => rollup.missing_days += 1,
+                }
+            }
+        }
+        rollups
+    }
+
+    /// The `days` dates before `date`, for comparing a day with recent ones.
+    /// Dates before the first recorded day are left out rather than counted as missing.
+    pub fn trailing_rollup(&self, date: NaiveDate, days: u64) -> Rollup {
+        let start = date - Days::new(days);
+        let mut rollup = Rollup::new(start, date.pred_opt().unwrap_or(date));
+        let first = match self.first_day() {
+            Some((first, _)) => *first,
+            None => return rollup,
+        };
+        for date in start.max(first).iter_days().take_while(|day| *day < date) {
+            match self.days.get(&date) {
+                Some(day) => rollup.add_day(day),
+                None => rollup.missing_days += 1,
+            }
+        }
+        rollup
+    }
+
+    pub(crate) fn insert_session(days: &mut BTreeMap<NaiveDate, Day>, options: &LoadOptions, session: Session) {
+        History::insert_session_on(days, options.workday(&session.datetime_range.start), session);
+    }
+
+    pub(crate) fn insert_session_on(days: &mut BTreeMap<NaiveDate, Day>, date: NaiveDate, session: Session) {
+        match days.entry(date) {
+            Entry::Occupied(mut day) => day.get_mut().add_session(session),
+            Entry::Vacant(day) => {
+                day.insert(Day::from_session(session));
+            }
+        }
+    }
+
+    /// Adds the sessions of another history to this one, summing the days both have.
+    /// Label each history with `set_source` first to be able to tell their values apart.
+    pub fn merge(&mut self, other: History) {
+        for (date, day) in other.days {
+            for session in day.sessions {
+                History::insert_session_on(&mut self.days, date, session);
+            }
+        }
+    }
+
+    /// Marks every session in this history as recorded by `source`
+    pub fn set_source(&mut self, source: &str) {
+        for session in self.days.values_mut().flat_map(|day| &mut day.sessions) {
+            session.source = Some(source.to_string());
+        }
+    }
+
+    /// Rescales the movement of every session from the `from` calibration to the `to` one,
+    /// so histories recorded on different monitors can be summed
+    pub fn convert_movement(&mut self, from: &MovementCalibration, to: &MovementCalibration) {
+        if from == to {
+            return;
+        }
+        for day in self.days.values_mut() {
+            let sessions = std::mem::take(&mut day.sessions).into_iter()
+                .map(|mut session| {
+                    session.stats.total_mouse_movement = from.convert(session.stats.total_mouse_movement, to);
+                    session.stats.total_mouse_click_movement = from.convert(session.stats.total_mouse_click_movement, to);
+                    session
+                })
+                .collect();
+            if let Some(converted) = Day::from_sessions(sessions) {
+                *day = converted;
+            }
+        }
+    }
+
+    /// The sources of the sessions in this history, in name order
+    pub fn sources(&self) -> Vec<String> {
+        let sources: BTreeSet<&String> = self.days.values()
+            .flat_map(|day| &day.sessions)
+            .filter_map(|session| session.source.as_ref())
+            .collect();
+        sources.into_iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_today_uses_timezone_override() {
+        let date = |day: u32| NaiveDate::from_ymd_opt(2022, 11, day).unwrap();
+        let mut options = LoadOptions::default();
+        options.timezone.default = Some(chrono_tz::Etc::GMTPlus12);
+        let now = Utc.with_ymd_and_hms(2022, 11, 8, 12, 0, 0).unwrap();
+        assert_eq!(options.today_at(now), date(8));
+
+        options.timezone.overrides.push(TimezoneOverride {
+            start: date(7),
+            end: date(9),
+            timezone: chrono_tz::Pacific::Kiritimati,
+        });
+        assert_eq!(options.today_at(now), date(9));
+    }
+
+    #[test]
+    fn test_movement_calibration() {
+        assert_eq!(MovementCalibration::default().meters(8576.0), 2.0);
+
+        // A 27" 2560x1440 monitor has ~108.8 pixels per inch
+        let calibration = MovementCalibration::from_monitor(27.0, 2560, 1440).unwrap();
+        assert!((calibration.pixels_per_meter - 4282.9).abs() < 0.1);
+        assert_eq!(MovementCalibration::from_monitor(0.0, 2560, 1440), None);
+    }
+}
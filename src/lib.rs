@@ -1,5 +1,12 @@
 mod app;
 pub use app::Informant;
-mod workrave;
+pub mod history;
+pub mod workrave;
 mod settings;
 mod stats;
+mod cache;
+mod watcher;
+mod today;
+mod detect;
+mod source;
+mod activitywatch;
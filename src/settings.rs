@@ -1,115 +1,1022 @@
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::fmt;
 use std::fs;
 use std::io;
-use std::io::{ErrorKind, Read};
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use eframe::egui;
 use egui::*;
-use whoami;
-use crate::workrave;
+use crate::{activitywatch, detect, history, source, workrave};
+use chrono::{Local, NaiveDate, NaiveTime, Timelike, Weekday};
+use chrono_tz::Tz;
 
 
 const SETTINGS_FILENAME: &str = "settings.json";
+/// When a file with this name is next to the executable, settings are kept there instead of the config directory
+const PORTABLE_MARKER_FILENAME: &str = "portable.txt";
+const CONFIG_DIRECTORY_NAME: &str = "informant";
+/// The current settings file layout, files without a version are version 1
+const SETTINGS_VERSION: u32 = 3;
+/// Upgrades a settings file from the version at the same index plus one to the next version
+const MIGRATIONS: [fn(&mut Map<String, Value>); SETTINGS_VERSION as usize - 1] = [
+    Settings::migrate_v1,
+    Settings::migrate_v2,
+];
+const DEFAULT_PROFILE_NAME: &str = "Default";
+/// Fields that moved from `Settings` into each `Profile` in version 3
+const PROFILE_FIELDS: [&str; 5] = ["workrave_historystats_path",
+                                   "workrave_todaystats_path",
+                                   "timezone",
+                                   "timezone_overrides",
+                                   "movement_calibration"];
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Settings {
+#[derive(Debug)]
+pub enum SettingsError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    NewerVersion { found: u64 },
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SettingsError::Io(error) => write!(f, "{}", error),
+            SettingsError::Json(error) => write!(f, "{}", error),
+            SettingsError::NewerVersion { found } => write!(f, "settings version {} is newer than the supported version {}",
+                                                             found, SETTINGS_VERSION),
+        }
+    }
+}
+
+impl std::error::Error for SettingsError {}
+
+impl From<io::Error> for SettingsError {
+    fn from(error: io::Error) -> Self {
+        SettingsError::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for SettingsError {
+    fn from(error: serde_json::Error) -> Self {
+        SettingsError::Json(error)
+    }
+}
+
+/// The data files of one machine and how to read them
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Profile {
+    pub name: String,
+    /// The tracker this profile's stats are loaded from
+    #[serde(default)]
+    pub source: source::SourceKind,
+    #[serde(default)]
     pub workrave_historystats_path: Option<String>,
+    #[serde(default)]
     pub workrave_todaystats_path: Option<String>,
+    /// A JSON bucket export from ActivityWatch, used when `source` is `SourceKind::ActivityWatch`
+    #[serde(default)]
+    pub activitywatch_export_path: Option<String>,
+    /// Timezone the Workrave stats were recorded in, `None` uses this machine's timezone
+    #[serde(default)]
+    pub timezone: Option<Tz>,
+    #[serde(default)]
+    pub timezone_overrides: Vec<history::TimezoneOverride>,
+    #[serde(default)]
+    pub movement_calibration: history::MovementCalibration,
+    /// Names of other profiles whose stats are added to this one's, e.g. ones for other machines
+    #[serde(default)]
+    pub merged_profiles: Vec<String>,
+}
+
+/// The files to load a profile's stats from and the options to load them with, named after the profile
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatsSource {
+    pub name: String,
+    pub kind: source::SourceKind,
+    /// Loaded with `DataSource::load_history` and reloaded whenever it changes
+    pub history_path: String,
+    /// Loaded with `DataSource::load_today` and merged over the history whenever it changes
+    pub today_path: Option<String>,
+    pub options: history::LoadOptions,
+    /// The movement the source records is converted from this to the active profile's calibration
+    pub calibration: history::MovementCalibration,
+}
+
+impl Profile {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            source: source::SourceKind::default(),
+            workrave_historystats_path: None,
+            workrave_todaystats_path: None,
+            activitywatch_export_path: None,
+            timezone: None,
+            timezone_overrides: vec![],
+            movement_calibration: history::MovementCalibration::default(),
+            merged_profiles: vec![],
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Settings {
+    pub version: u32,
+    /// Never empty, `Settings::from_json` adds a default profile to files without one
+    pub profiles: Vec<Profile>,
+    pub active_profile: usize,
+    /// Time a workday starts at, sessions starting earlier count towards the previous day
+    #[serde(default)]
+    pub day_start: NaiveTime,
+    #[serde(default = "Settings::default_week_start")]
+    pub week_start: Weekday,
+    /// Why the settings file couldn't be loaded, if defaults are being used instead of it
+    #[serde(skip)]
+    pub load_warning: Option<String>,
+    /// Why the settings couldn't be saved the last time they changed
+    #[serde(skip)]
+    pub save_error: Option<String>,
+    /// Set when the settings file is from a newer version, so it isn't overwritten until the user chooses to
+    #[serde(skip)]
+    keep_newer_file: bool,
 }
 
 impl Settings {
     pub fn default() -> Self {
         let mut settings = Self {
-            workrave_historystats_path: None,
-            workrave_todaystats_path: None,
+            version: SETTINGS_VERSION,
+            profiles: vec![Profile::new(DEFAULT_PROFILE_NAME)],
+            active_profile: 0,
+            day_start: NaiveTime::MIN,
+            week_start: Settings::default_week_start(),
+            load_warning: None,
+            save_error: None,
+            keep_newer_file: false,
         };
         settings.init();
         settings
     }
 
-    fn init(&mut self) {
-        match fs::File::open(SETTINGS_FILENAME) {
-            Ok(_) => {
-                self.load_settings().unwrap();
-            }
-            Err(_) => {
-                println!("No settings file found");
-                self.workrave_historystats_path = Settings::try_workrave_appdata_path("historystats");
-                self.workrave_todaystats_path = Settings::try_workrave_appdata_path("todaystats");
-                self.save_settings().unwrap();
-            }
-        };
+    fn default_week_start() -> Weekday {
+        Weekday::Mon
     }
 
-    fn load_settings(&mut self) -> io::Result<bool> {
-        match fs::File::open(SETTINGS_FILENAME) {
-            Ok(mut file) => {
-                let mut contents = String::new();
-                file.read_to_string(&mut contents)?;
-                let settings: Settings = serde_json::from_str(&contents)?;
-                self.workrave_historystats_path = settings.workrave_historystats_path;
-                self.workrave_todaystats_path = settings.workrave_todaystats_path;
-                Ok(true)
-            }
-            Err(_) => {
-                println!("No existing settings file, saving current settings...");
-                match self.save_settings() {
-                    Ok(_) => println!("Successfully saved settings"),
-                    Err(error) => {
-                        println!("Failed to save settings: {:?}", error);
-                    }
+    pub fn profile(&self) -> &Profile {
+        &self.profiles[self.active_profile]
+    }
+
+    pub fn profile_mut(&mut self) -> &mut Profile {
+        &mut self.profiles[self.active_profile]
+    }
+
+    pub fn load_options(&self) -> history::LoadOptions {
+        self.load_options_for(self.profile())
+    }
+
+    fn load_options_for(&self, profile: &Profile) -> history::LoadOptions {
+        history::LoadOptions {
+            timezone: history::TimezoneConfig {
+                default: profile.timezone,
+                overrides: profile.timezone_overrides.clone(),
+            },
+            day_start: self.day_start,
+        }
+    }
+
+    /// The active profile followed by the profiles merged into it, leaving out any without a history path
+    pub fn stats_sources(&self) -> Vec<StatsSource> {
+        let merged = self.profile().merged_profiles.iter().filter_map(|name| {
+            self.profiles.iter().enumerate()
+                .find(|(i, profile)| *i != self.active_profile && profile.name == *name)
+                .map(|(_, profile)| profile)
+        });
+        std::iter::once(self.profile())
+            .chain(merged)
+            .filter_map(|profile| {
+                let (history_path, today_path) = match profile.source {
+                    source::SourceKind::Workrave => (profile.workrave_historystats_path.clone()?, profile.workrave_todaystats_path.clone()),
+                    source::SourceKind::ActivityWatch => (profile.activitywatch_export_path.clone()?, None),
                 };
-                Ok(false)
+                Some(StatsSource {
+                    name: profile.name.clone(),
+                    kind: profile.source,
+                    history_path,
+                    today_path,
+                    options: self.load_options_for(profile),
+                    calibration: profile.movement_calibration,
+                })
+            })
+            .collect()
+    }
+
+    /// Renames the active profile, returning whether the name changed.
+    /// Profiles are merged by name, so a name another profile already has is refused.
+    fn rename_profile(&mut self, name: &str) -> Result<bool, String> {
+        let name = name.trim();
+        if name == self.profile().name {
+            return Ok(false);
+        }
+        if name.is_empty() {
+            return Err("Profile names can't be empty".to_string());
+        }
+        if self.profiles.iter().enumerate().any(|(i, profile)| i != self.active_profile && profile.name == name) {
+            return Err(format!("Another profile is already called \"{}\"", name));
+        }
+        let old_name = std::mem::replace(&mut self.profile_mut().name, name.to_string());
+        self.rename_merged_profile(&old_name, name);
+        Ok(true)
+    }
+
+    /// The first of `names` that none of `profiles` has
+    fn first_unused_name(profiles: &[Profile], mut names: impl Iterator<Item = String>) -> String {
+        names.find(|name| !profiles.iter().any(|profile| profile.name == *name)).unwrap_or_default()
+    }
+
+    /// A name for a new profile, numbered after the existing ones
+    fn new_profile_name(&self) -> String {
+        Settings::first_unused_name(&self.profiles, (self.profiles.len() + 1..).map(|n| format!("Profile {}", n)))
+    }
+
+    /// Keeps profiles that include `old_name` including it after a rename
+    fn rename_merged_profile(&mut self, old_name: &str, new_name: &str) {
+        for merged in self.profiles.iter_mut().flat_map(|profile| &mut profile.merged_profiles) {
+            if merged == old_name {
+                *merged = new_name.to_string();
             }
         }
     }
 
-    fn try_workrave_appdata_path(filename: &str) -> Option<String> {
-        println!("Looking for {} in default AppData path...", filename);
-        let path = format!("C:\\Users\\{}\\AppData\\Roaming\\Workrave\\{}", whoami::username(), filename);
-        if workrave::WorkraveHistory::is_file_valid(&path) {
-            println!("Found {}", filename);
-            Some(path)
-        } else {
-            None
+    /// Where settings are stored, next to the executable in portable mode and in the OS config directory otherwise
+    pub fn settings_path() -> PathBuf {
+        let exe_dir = std::env::current_exe().ok().and_then(|exe| exe.parent().map(Path::to_path_buf));
+        Settings::settings_path_in(exe_dir.as_deref(), dirs::config_dir().as_deref())
+    }
+
+    fn settings_path_in(exe_dir: Option<&Path>, config_dir: Option<&Path>) -> PathBuf {
+        match (exe_dir, config_dir) {
+            (Some(exe_dir), _) if exe_dir.join(PORTABLE_MARKER_FILENAME).is_file() => exe_dir.join(SETTINGS_FILENAME),
+            (_, Some(config_dir)) => config_dir.join(CONFIG_DIRECTORY_NAME).join(SETTINGS_FILENAME),
+            (Some(exe_dir), None) => exe_dir.join(SETTINGS_FILENAME),
+            (None, None) => PathBuf::from(SETTINGS_FILENAME),
         }
     }
 
-    fn save_settings(&self) -> io::Result<bool> {
-        let serialised = serde_json::to_string(&self)?;
-        match fs::File::open(SETTINGS_FILENAME) {
-            Ok(file) => file,
-            Err(error) => match error.kind() {
-                ErrorKind::NotFound => match fs::File::create(SETTINGS_FILENAME) {
-                    Ok(new_file) => {
-                        println!("Settings file created");
-                        new_file
+    /// Copies a settings file left in the working directory by older versions to `settings_path`
+    fn migrate_working_directory_settings(settings_path: &Path) {
+        let old_path = Path::new(SETTINGS_FILENAME);
+        if settings_path.exists() || !old_path.is_file() {
+            return;
+        }
+        // Other apps name their settings the same, only a file written by this app is adopted
+        let is_ours = fs::read_to_string(old_path).is_ok_and(|contents| Settings::is_v1_settings(&contents));
+        if !is_ours {
+            println!("Not copying {} from the working directory, it isn't an Informant settings file", SETTINGS_FILENAME);
+            return;
+        }
+        if let Some(parent) = settings_path.parent() {
+            let _ = fs::create_dir_all(parent);
+        }
+        match fs::copy(old_path, settings_path) {
+            Ok(_) => println!("Copied settings from the working directory to {}", settings_path.display()),
+            Err(error) => println!("Failed to copy settings to {}: {}", settings_path.display(), error),
+        }
+    }
+
+    /// Whether `contents` looks like a settings file from before they were stored in the config directory,
+    /// which always had the `workrave_historystats_path` field
+    fn is_v1_settings(contents: &str) -> bool {
+        serde_json::from_str::<Map<String, Value>>(contents)
+            .is_ok_and(|settings| settings.contains_key("workrave_historystats_path"))
+    }
+
+    fn init(&mut self) {
+        let settings_path = Settings::settings_path();
+        Settings::migrate_working_directory_settings(&settings_path);
+        self.load_or_replace(&settings_path);
+    }
+
+    /// Loads the settings file, falling back to defaults and saving them when it is missing or can't be loaded
+    fn load_or_replace(&mut self, settings_path: &Path) {
+        if !settings_path.exists() {
+            println!("No settings file found");
+            self.detect_paths();
+            self.save_and_report();
+            return;
+        }
+
+        if let Err(error) = self.load_settings(settings_path) {
+            println!("Failed to load settings: {}", error);
+            self.detect_paths();
+            if let SettingsError::NewerVersion { .. } = error {
+                // Running an older build shouldn't lose the newer build's settings
+                self.keep_newer_file = true;
+                self.load_warning = Some(format!("Settings could not be loaded ({}), defaults are being used and changes \
+                                                  won't be saved unless you overwrite them", error));
+                return;
+            }
+            // An unreadable file is left alone, anything else is moved aside so defaults can be saved in its place
+            let backup = match error {
+                SettingsError::Io(_) => None,
+                _ => match Settings::back_up(settings_path) {
+                    Ok(backup) => Some(backup),
+                    Err(backup_error) => {
+                        println!("Failed to back up settings: {}", backup_error);
+                        None
                     }
-                    Err(e) => panic!("Unknown failure when creating settings file: {:?}", e)
                 },
-                other_error => {
-                    panic!("Unknown failure when opening settings file: {:?}", other_error)
+            };
+            self.load_warning = Some(match &backup {
+                Some(backup) => format!("Settings could not be loaded ({}), they were backed up to {} and defaults are being used",
+                                        error, backup.display()),
+                None => format!("Settings could not be loaded ({}), defaults are being used", error),
+            });
+            if backup.is_some() {
+                self.save_and_report();
+            }
+        }
+    }
+
+    fn detect_paths(&mut self) {
+        let profile = self.profile_mut();
+        profile.workrave_historystats_path = detect::detect(workrave::WORKRAVE_HISTORYSTATS_FILENAME).found;
+        profile.workrave_todaystats_path = detect::detect(workrave::WORKRAVE_TODAYSTATS_FILENAME).found;
+    }
+
+    fn load_settings(&mut self, settings_path: &Path) -> Result<(), SettingsError> {
+        let contents = fs::read_to_string(settings_path)?;
+        let settings = Settings::from_json(&contents)?;
+        self.version = settings.version;
+        self.profiles = settings.profiles;
+        self.active_profile = settings.active_profile;
+        self.day_start = settings.day_start;
+        self.week_start = settings.week_start;
+        Ok(())
+    }
+
+    /// Parses a settings file of any version up to `SETTINGS_VERSION`, migrating it to the current layout
+    fn from_json(contents: &str) -> Result<Settings, SettingsError> {
+        let mut value: Value = serde_json::from_str(contents)?;
+        let object = value.as_object_mut()
+            .ok_or_else(|| <serde_json::Error as serde::de::Error>::custom("settings are not a JSON object"))?;
+
+        let found = object.get("version").and_then(Value::as_u64).unwrap_or(1);
+        if found > SETTINGS_VERSION as u64 {
+            return Err(SettingsError::NewerVersion { found });
+        }
+        for migration in &MIGRATIONS[(found as usize).max(1) - 1..] {
+            migration(object);
+        }
+        object.insert("version".to_string(), Value::from(SETTINGS_VERSION));
+
+        let mut settings: Settings = serde_json::from_value(value)?;
+        if settings.profiles.is_empty() {
+            settings.profiles.push(Profile::new(DEFAULT_PROFILE_NAME));
+        }
+        // Older versions allowed empty and repeated names, profiles are found by name so each gets its own
+        for i in 0..settings.profiles.len() {
+            let name = match settings.profiles[i].name.trim() {
+                "" => "Profile".to_string(),
+                name => name.to_string(),
+            };
+            let names = std::iter::once(name.clone()).chain((2..).map(|n| format!("{} {}", name, n)));
+            settings.profiles[i].name = Settings::first_unused_name(&settings.profiles[..i], names);
+        }
+        settings.active_profile = settings.active_profile.min(settings.profiles.len() - 1);
+        Ok(settings)
+    }
+
+    /// Version 1 files have no version field and were kept in the working directory, so relative stats
+    /// paths in them are made absolute before the file is stored elsewhere
+    fn migrate_v1(settings: &mut Map<String, Value>) {
+        if let Ok(working_directory) = std::env::current_dir() {
+            Settings::make_paths_absolute(settings, &working_directory);
+        }
+    }
+
+    fn make_paths_absolute(settings: &mut Map<String, Value>, base: &Path) {
+        for field in ["workrave_historystats_path", "workrave_todaystats_path"] {
+            if let Some(Value::String(path)) = settings.get_mut(field) {
+                if !path.is_empty() && Path::new(path.as_str()).is_relative() {
+                    *path = base.join(path.as_str()).display().to_string();
                 }
             }
+        }
+    }
+
+    /// Version 2 had a single set of paths, timezones and calibration, they become the first profile
+    fn migrate_v2(settings: &mut Map<String, Value>) {
+        let mut profile = Map::new();
+        profile.insert("name".to_string(), Value::from(DEFAULT_PROFILE_NAME));
+        for field in PROFILE_FIELDS {
+            if let Some(value) = settings.remove(field) {
+                profile.insert(field.to_string(), value);
+            }
+        }
+        settings.insert("profiles".to_string(), Value::Array(vec![Value::Object(profile)]));
+        settings.insert("active_profile".to_string(), Value::from(0));
+    }
+
+    /// Renames a settings file that couldn't be loaded so it isn't overwritten, returning where it went
+    fn back_up(settings_path: &Path) -> io::Result<PathBuf> {
+        let backup = settings_path.with_extension(format!("{}.bak", Local::now().format("%Y%m%d-%H%M%S")));
+        fs::rename(settings_path, &backup)?;
+        Ok(backup)
+    }
+
+    /// Saves the settings, replacing the file in one step so a failed save leaves the previous one intact
+    fn save_settings(&self) -> Result<(), SettingsError> {
+        let serialised = serde_json::to_string(&self)?;
+        let settings_path = Settings::settings_path();
+        if let Some(parent) = settings_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        write_atomically(&settings_path, serialised.as_bytes())?;
+        Ok(())
+    }
+
+    /// Saves the settings, keeping any error in `save_error` to be shown in the Settings tab.
+    /// Does nothing while a newer version's settings file is being kept.
+    fn save_and_report(&mut self) {
+        if self.keep_newer_file {
+            return;
+        }
+        self.save_error = match self.save_settings() {
+            Ok(()) => None,
+            Err(error) => {
+                println!("Failed to save settings: {}", error);
+                Some(format!("Settings could not be saved to {}: {}", Settings::settings_path().display(), error))
+            }
+        };
+    }
+}
+
+/// Writes `contents` to a temporary file next to `path` then renames it over `path`
+fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+
+    let result = fs::File::create(&temp_path)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// A path being edited in the Settings tab, checked as it is typed
+struct PathInput {
+    text: String,
+    status: PathStatus,
+    /// Checks a non-empty path
+    check: fn(&str) -> PathStatus,
+    /// Whether `check` is cheap enough to run on every keystroke, otherwise it runs once the field loses focus
+    check_while_typing: bool,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+enum PathStatus {
+    Empty,
+    /// Edited but not checked yet
+    Unchecked,
+    Valid(workrave::FormatVersion),
+    ValidExport,
+    Invalid(String),
+}
+
+impl PathInput {
+    /// An input for a Workrave stats file, only its header is read to check it
+    fn new(path: &Option<String>) -> Self {
+        PathInput::with_check(path, PathInput::check_stats_file, true)
+    }
+
+    /// An input for an ActivityWatch bucket export, which has to be read to the end to check it
+    fn for_export(path: &Option<String>) -> Self {
+        PathInput::with_check(path, PathInput::check_export, false)
+    }
+
+    fn with_check(path: &Option<String>, check: fn(&str) -> PathStatus, check_while_typing: bool) -> Self {
+        let mut input = Self {
+            text: String::new(),
+            status: PathStatus::Empty,
+            check,
+            check_while_typing,
         };
+        input.set_text(path.clone().unwrap_or_default());
+        input
+    }
+
+    fn set_text(&mut self, text: String) {
+        self.text = text;
+        let path = self.text.trim();
+        self.status = if path.is_empty() { PathStatus::Empty } else { (self.check)(path) };
+    }
+
+    fn check_stats_file(path: &str) -> PathStatus {
+        match history::History::detect_version(path) {
+            Ok(version) => PathStatus::Valid(version),
+            Err(error) => PathStatus::Invalid(error.to_string()),
+        }
+    }
+
+    fn check_export(path: &str) -> PathStatus {
+        match activitywatch::ActivityWatchSource::check_export(path) {
+            Ok(()) => PathStatus::ValidExport,
+            Err(error) => PathStatus::Invalid(error.to_string()),
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        matches!(self.status, PathStatus::Valid(_) | PathStatus::ValidExport)
+    }
 
-        match fs::write(SETTINGS_FILENAME, serialised.as_bytes()) {
-            Ok(_) => Ok(true),
-            Err(error) => panic!("Failed to write settings to file: {:?}", error)
+    /// The text field, returns true when `path` was changed, which only happens once the text is valid
+    fn text_edit_ui(&mut self, ui: &mut Ui, path: &mut Option<String>) -> bool {
+        let response = ui.add(TextEdit::singleline(&mut self.text).desired_width(400.0));
+        if response.changed() && !self.check_while_typing {
+            self.status = PathStatus::Unchecked;
+        }
+        let check = match self.check_while_typing {
+            true => response.changed(),
+            false => response.lost_focus() && self.status == PathStatus::Unchecked,
+        };
+        if check {
+            self.set_text(self.text.clone());
+            if self.is_valid() && path.as_deref() != Some(self.text.trim()) {
+                *path = Some(self.text.trim().to_string());
+                return true;
+            }
         }
+        false
+    }
+
+    fn status_ui(&self, ui: &mut Ui) {
+        let valid = Color32::from_rgb(0, 202, 252);
+        match &self.status {
+            PathStatus::Empty => ui.label(RichText::new("No path set").color(ui.visuals().weak_text_color())),
+            PathStatus::Unchecked => ui.label(RichText::new("Checked once you press Enter").color(ui.visuals().weak_text_color())),
+            PathStatus::Valid(version) => ui.label(RichText::new(format!("Valid, version {}", version.number())).color(valid)),
+            PathStatus::ValidExport => ui.label(RichText::new("Valid").color(valid)),
+            PathStatus::Invalid(reason) => ui.label(RichText::new(reason).color(Color32::from_rgb(221, 18, 101))),
+        };
     }
 }
 
 pub struct SettingsTab {
     pub settings: Settings,
+    historystats_input: PathInput,
+    todaystats_input: PathInput,
+    export_input: PathInput,
+    override_start: String,
+    override_end: String,
+    override_timezone: Tz,
+    override_error: Option<String>,
+    monitor_diagonal_inches: f64,
+    monitor_width_pixels: u32,
+    monitor_height_pixels: u32,
+    historystats_detection: detect::Detection,
+    todaystats_detection: detect::Detection,
+    /// The name being typed for the active profile, applied once the field loses focus
+    profile_name: String,
+    profile_name_error: Option<String>,
 }
 
 impl SettingsTab {
-    fn filepath_ui(ui: &mut Ui, path: &Option<String>) {
-        if let Some(saved_path) = &path {
-            ui.code(saved_path);
-        } else {
-            ui.code("! no path set !");
+    pub fn default() -> Self {
+        let settings = Settings::default();
+        Self {
+            historystats_input: PathInput::new(&settings.profile().workrave_historystats_path),
+            todaystats_input: PathInput::new(&settings.profile().workrave_todaystats_path),
+            export_input: PathInput::for_export(&settings.profile().activitywatch_export_path),
+            profile_name: settings.profile().name.clone(),
+            settings,
+            override_start: String::new(),
+            override_end: String::new(),
+            override_timezone: Tz::UTC,
+            override_error: None,
+            monitor_diagonal_inches: 24.0,
+            monitor_width_pixels: 1920,
+            monitor_height_pixels: 1080,
+            historystats_detection: detect::detect(workrave::WORKRAVE_HISTORYSTATS_FILENAME),
+            todaystats_detection: detect::detect(workrave::WORKRAVE_TODAYSTATS_FILENAME),
+            profile_name_error: None,
+        }
+    }
+
+    /// Switches to the profile at `index`, returning true if it wasn't already the active one
+    pub fn select_profile(&mut self, index: usize) -> bool {
+        if index == self.settings.active_profile || index >= self.settings.profiles.len() {
+            return false;
+        }
+        self.activate_profile(index);
+        true
+    }
+
+    fn activate_profile(&mut self, index: usize) {
+        self.settings.active_profile = index;
+        self.historystats_input = PathInput::new(&self.settings.profile().workrave_historystats_path);
+        self.todaystats_input = PathInput::new(&self.settings.profile().workrave_todaystats_path);
+        self.export_input = PathInput::for_export(&self.settings.profile().activitywatch_export_path);
+        self.profile_name = self.settings.profile().name.clone();
+        self.profile_name_error = None;
+        self.settings.save_and_report();
+    }
+
+    /// A drop-down of the profiles, returns true when a different profile was picked
+    pub fn profile_switcher_ui(&mut self, ui: &mut Ui) -> bool {
+        let mut selected = self.settings.active_profile;
+        ComboBox::from_id_source("profile_switcher")
+            .selected_text(&self.settings.profile().name)
+            .show_ui(ui, |ui| {
+                for (i, profile) in self.settings.profiles.iter().enumerate() {
+                    ui.selectable_value(&mut selected, i, &profile.name);
+                }
+            });
+        self.select_profile(selected)
+    }
+
+    /// Returns whether the active profile was renamed, and whether a different profile is now active
+    fn profiles_ui(&mut self, ui: &mut Ui) -> (bool, bool) {
+        let mut renamed = false;
+        let mut switched = false;
+        ui.heading(RichText::new("Profile"));
+        ui.separator();
+        ui.horizontal(|ui| {
+            ui.label("Name");
+            if ui.add(TextEdit::singleline(&mut self.profile_name).desired_width(200.0)).lost_focus() {
+                match self.settings.rename_profile(&self.profile_name) {
+                    Ok(changed) => {
+                        renamed = changed;
+                        self.profile_name_error = None;
+                    }
+                    Err(error) => self.profile_name_error = Some(error),
+                }
+                self.profile_name = self.settings.profile().name.clone();
+            }
+            if ui.button("New Profile").clicked() {
+                let name = self.settings.new_profile_name();
+                self.settings.profiles.push(Profile::new(&name));
+                switched |= self.select_profile(self.settings.profiles.len() - 1);
+            }
+            let can_delete = self.settings.profiles.len() > 1;
+            if ui.add_enabled(can_delete, Button::new("Delete Profile")).clicked() {
+                let removed = self.settings.active_profile;
+                let name = self.settings.profiles.remove(removed).name;
+                for profile in &mut self.settings.profiles {
+                    profile.merged_profiles.retain(|merged| *merged != name);
+                }
+                self.activate_profile(removed.saturating_sub(1));
+                switched = true;
+            }
+        });
+        if let Some(error) = &self.profile_name_error {
+            ui.label(RichText::new(error).color(Color32::from_rgb(221, 18, 101)));
+        }
+        (renamed, switched)
+    }
+
+    /// Checkboxes for the other profiles to add to this one, returns true if one was changed
+    fn merged_profiles_ui(&mut self, ui: &mut Ui) -> bool {
+        if self.settings.profiles.len() < 2 {
+            return false;
+        }
+        let mut changed = false;
+        let names: Vec<String> = self.settings.profiles.iter().enumerate()
+            .filter(|(i, _)| *i != self.settings.active_profile)
+            .map(|(_, profile)| profile.name.clone())
+            .collect();
+        ui.horizontal_wrapped(|ui| {
+            ui.label("Include stats from");
+            let merged = &mut self.settings.profile_mut().merged_profiles;
+            for name in names {
+                let mut included = merged.contains(&name);
+                if ui.checkbox(&mut included, &name).changed() {
+                    match included {
+                        true => merged.push(name),
+                        false => merged.retain(|merged| *merged != name),
+                    }
+                    changed = true;
+                }
+            }
+        });
+        changed
+    }
+
+    /// Problems loading or saving the settings file
+    fn status_ui(&mut self, ui: &mut Ui) {
+        if self.settings.load_warning.is_none() && self.settings.save_error.is_none() {
+            return;
+        }
+        if let Some(warning) = self.settings.load_warning.clone() {
+            ui.horizontal(|ui| {
+                ui.label(RichText::new(warning).color(Color32::from_rgb(255, 195, 29)));
+                if self.settings.keep_newer_file && ui.button("Overwrite").clicked() {
+                    self.settings.keep_newer_file = false;
+                    self.settings.load_warning = None;
+                    self.settings.save_and_report();
+                }
+            });
+        }
+        if let Some(error) = self.settings.save_error.clone() {
+            ui.horizontal(|ui| {
+                ui.label(RichText::new(error).color(Color32::from_rgb(221, 18, 101)));
+                if ui.button("Retry").clicked() {
+                    self.settings.save_and_report();
+                }
+            });
+        }
+        ui.add_space(10.0);
+    }
+
+    /// Lists every location auto-detection looked in and what it found there
+    fn detection_ui(&mut self, ui: &mut Ui) {
+        CollapsingHeader::new("Auto-detected locations").show(ui, |ui| {
+            for (filename, detection) in [(workrave::WORKRAVE_HISTORYSTATS_FILENAME, &self.historystats_detection),
+                                          (workrave::WORKRAVE_TODAYSTATS_FILENAME, &self.todaystats_detection)] {
+                ui.label(filename);
+                Grid::new(format!("{}_candidates", filename)).show(ui, |ui| {
+                    for candidate in &detection.candidates {
+                        ui.code(&candidate.path);
+                        let color = match candidate.status {
+                            detect::CandidateStatus::Valid => Color32::from_rgb(0, 202, 252),
+                            detect::CandidateStatus::Invalid => Color32::from_rgb(221, 18, 101),
+                            detect::CandidateStatus::Missing => ui.visuals().weak_text_color(),
+                        };
+                        ui.label(RichText::new(candidate.status.name()).color(color));
+                        ui.end_row();
+                    }
+                });
+            }
+            if ui.button("Search again").clicked() {
+                self.historystats_detection = detect::detect(workrave::WORKRAVE_HISTORYSTATS_FILENAME);
+                self.todaystats_detection = detect::detect(workrave::WORKRAVE_TODAYSTATS_FILENAME);
+            }
+        });
+    }
+
+    /// Returns true when the calibration was changed
+    fn calibration_ui(&mut self, ui: &mut Ui) -> bool {
+        let mut changed = false;
+        ui.heading(RichText::new("Mouse Movement"));
+        ui.separator();
+        ui.horizontal(|ui| {
+            ui.label("Screen pixels per meter");
+            changed |= ui.add(DragValue::new(&mut self.settings.profile_mut().movement_calibration.pixels_per_meter)
+                .clamp_range(1.0..=100_000.0)
+                .speed(10.0)
+                .max_decimals(1)).changed();
+            if ui.button("Reset").clicked() {
+                self.settings.profile_mut().movement_calibration = history::MovementCalibration::default();
+                changed = true;
+            }
+        });
+
+        CollapsingHeader::new("Calibrate from monitor size").show(ui, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Diagonal");
+                ui.add(DragValue::new(&mut self.monitor_diagonal_inches).clamp_range(1.0..=200.0).speed(0.1).suffix("\""));
+                ui.label("Resolution");
+                ui.add(DragValue::new(&mut self.monitor_width_pixels).clamp_range(1..=20_000));
+                ui.label("x");
+                ui.add(DragValue::new(&mut self.monitor_height_pixels).clamp_range(1..=20_000));
+                if ui.button("Apply").clicked() {
+                    if let Some(calibration) = history::MovementCalibration::from_monitor(self.monitor_diagonal_inches,
+                                                                                            self.monitor_width_pixels,
+                                                                                            self.monitor_height_pixels) {
+                        self.settings.profile_mut().movement_calibration = calibration;
+                        changed = true;
+                    }
+                }
+            });
+        });
+        changed
+    }
+
+    fn timezone_name(timezone: &Option<Tz>) -> &'static str {
+        match timezone {
+            Some(tz) => tz.name(),
+            None => "System timezone",
+        }
+    }
+
+    fn timezone_combo_box(ui: &mut Ui, id: &str, selected: &mut Option<Tz>, allow_system: bool) -> bool {
+        let mut changed = false;
+        ComboBox::from_id_source(id)
+            .selected_text(SettingsTab::timezone_name(selected))
+            .width(220.0)
+            .show_ui(ui, |ui| {
+                if allow_system {
+                    changed |= ui.selectable_value(selected, None, SettingsTab::timezone_name(&None)).changed();
+                }
+                for tz in chrono_tz::TZ_VARIANTS {
+                    changed |= ui.selectable_value(selected, Some(tz), tz.name()).changed();
+                }
+            });
+        changed
+    }
+
+    /// Returns true when the day start was changed
+    fn day_start_ui(&mut self, ui: &mut Ui) -> bool {
+        let mut hour = self.settings.day_start.hour();
+        let mut minute = self.settings.day_start.minute();
+        let mut changed = false;
+        ui.horizontal(|ui| {
+            ui.label("Day starts at");
+            changed |= ui.add(DragValue::new(&mut hour).clamp_range(0..=23).custom_formatter(|n, _| format!("{:02}", n))).changed();
+            ui.label(":");
+            changed |= ui.add(DragValue::new(&mut minute).clamp_range(0..=59).custom_formatter(|n, _| format!("{:02}", n))).changed();
+        }).response.on_hover_text("Sessions that start before this time count towards the previous day");
+
+        if changed {
+            if let Some(day_start) = NaiveTime::from_hms_opt(hour, minute, 0) {
+                self.settings.day_start = day_start;
+            }
+        }
+        changed
+    }
+
+    /// Returns true when the week start was changed
+    fn week_start_ui(&mut self, ui: &mut Ui) -> bool {
+        let mut changed = false;
+        ui.horizontal(|ui| {
+            ui.label("Weeks start on");
+            ComboBox::from_id_source("week_start")
+                .selected_text(format!("{:?}", self.settings.week_start))
+                .show_ui(ui, |ui| {
+                    for weekday in [Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu,
+                                    Weekday::Fri, Weekday::Sat, Weekday::Sun] {
+                        changed |= ui.selectable_value(&mut self.settings.week_start, weekday, format!("{:?}", weekday)).changed();
+                    }
+                });
+        });
+        changed
+    }
+
+    /// A drop-down of the trackers stats can be loaded from, returns true when a different one was picked
+    fn source_ui(&mut self, ui: &mut Ui) -> bool {
+        let mut changed = false;
+        ui.horizontal(|ui| {
+            ui.label("Data source");
+            let selected = &mut self.settings.profile_mut().source;
+            ComboBox::from_id_source("data_source")
+                .selected_text(selected.name())
+                .show_ui(ui, |ui| {
+                    for kind in source::SourceKind::ALL {
+                        changed |= ui.selectable_value(selected, kind, kind.name()).changed();
+                    }
+                });
+        });
+        changed
+    }
+
+    /// The paths of Workrave's stats files, returns true when one was changed
+    fn workrave_ui(&mut self, ui: &mut Ui) -> bool {
+        let mut changed = false;
+        ui.heading(RichText::new("Workrave"));
+        ui.separator();
+        Grid::new("workrave_paths").num_columns(6).show(ui, |ui| {
+            let profile = self.settings.profile_mut();
+            changed |= SettingsTab::path_ui(ui, workrave::WORKRAVE_HISTORYSTATS_FILENAME,
+                                            &mut self.historystats_input,
+                                            &mut profile.workrave_historystats_path,
+                                            &mut self.historystats_detection);
+            changed |= SettingsTab::path_ui(ui, workrave::WORKRAVE_TODAYSTATS_FILENAME,
+                                            &mut self.todaystats_input,
+                                            &mut profile.workrave_todaystats_path,
+                                            &mut self.todaystats_detection);
+        });
+        self.detection_ui(ui);
+        changed
+    }
+
+    /// The path of the ActivityWatch export, returns true when it was changed
+    fn activitywatch_ui(&mut self, ui: &mut Ui) -> bool {
+        let mut changed = false;
+        ui.heading(RichText::new("ActivityWatch"));
+        ui.separator();
+        ui.horizontal(|ui| {
+            let path = &mut self.settings.profile_mut().activitywatch_export_path;
+            ui.label("Export filepath");
+            changed |= self.export_input.text_edit_ui(ui, path);
+
+            let mut new_path: Option<Option<String>> = None;
+            if let Some(selected) = SettingsTab::file_selection_dialog(ui, "Select an ActivityWatch bucket export", "aw-buckets-export.json") {
+                new_path = Some(Some(selected));
+            }
+            if ui.button("Clear").clicked() {
+                new_path = Some(None);
+            }
+            if let Some(new_path) = new_path {
+                self.export_input.set_text(new_path.clone().unwrap_or_default());
+                changed |= *path != new_path;
+                *path = new_path;
+            }
+            self.export_input.status_ui(ui);
+        });
+        ui.label("Export the afk and input buckets with \"Export all buckets as JSON\" on ActivityWatch's Raw Data page, \
+                  the file is read without ActivityWatch running");
+        changed
+    }
+
+    /// Returns true when the timezone settings were changed
+    fn timezone_ui(&mut self, ui: &mut Ui) -> bool {
+        let mut changed = false;
+        ui.heading(RichText::new("Dates"));
+        ui.separator();
+        ui.horizontal(|ui| {
+            ui.label("Stats were recorded in");
+            changed |= SettingsTab::timezone_combo_box(ui, "timezone", &mut self.settings.profile_mut().timezone, true);
+        });
+        changed |= self.day_start_ui(ui);
+
+        ui.label("Overrides, for dates recorded in a different timezone:");
+        let mut removed_index: Option<usize> = None;
+        for (i, timezone_override) in self.settings.profile().timezone_overrides.iter().enumerate() {
+            ui.horizontal(|ui| {
+                ui.code(format!("{} to {}: {}",
+                                timezone_override.start,
+                                timezone_override.end,
+                                timezone_override.timezone.name()));
+                if ui.button("Remove").clicked() {
+                    removed_index = Some(i);
+                }
+            });
+        }
+        if let Some(i) = removed_index {
+            self.settings.profile_mut().timezone_overrides.remove(i);
+            changed = true;
+        }
+
+        ui.horizontal(|ui| {
+            ui.label("From");
+            ui.add(TextEdit::singleline(&mut self.override_start).hint_text("YYYY-MM-DD").desired_width(90.0));
+            ui.label("to");
+            ui.add(TextEdit::singleline(&mut self.override_end).hint_text("YYYY-MM-DD").desired_width(90.0));
+            let mut timezone = Some(self.override_timezone);
+            SettingsTab::timezone_combo_box(ui, "override_timezone", &mut timezone, false);
+            self.override_timezone = timezone.unwrap_or(Tz::UTC);
+
+            if ui.button("Add Override").clicked() {
+                let start = NaiveDate::parse_from_str(self.override_start.trim(), "%Y-%m-%d");
+                let end = NaiveDate::parse_from_str(self.override_end.trim(), "%Y-%m-%d");
+                match (start, end) {
+                    (Ok(start), Ok(end)) if start <= end => {
+                        self.settings.profile_mut().timezone_overrides.push(history::TimezoneOverride {
+                            start,
+                            end,
+                            timezone: self.override_timezone,
+                        });
+                        self.override_start.clear();
+                        self.override_end.clear();
+                        self.override_error = None;
+                        changed = true;
+                    }
+                    (Ok(_), Ok(_)) => self.override_error = Some("Start date is after end date".to_string()),
+                    _ => self.override_error = Some("Dates must be written as YYYY-MM-DD".to_string()),
+                }
+            }
+        });
+        if let Some(error) = &self.override_error {
+            ui.label(RichText::new(error).color(Color32::from_rgb(221, 18, 101)));
+        }
+        changed
+    }
+
+    /// An editable path with its status and buttons to browse for, auto-detect or clear it.
+    /// Returns true when the saved path was changed, which only happens once the text is a valid stats file.
+    fn path_ui(ui: &mut Ui, filename: &str, input: &mut PathInput, path: &mut Option<String>,
+               detection: &mut detect::Detection) -> bool {
+        let mut changed = false;
+        ui.label(format!("{} filepath", filename));
+        changed |= input.text_edit_ui(ui, path);
+
+        let mut new_path: Option<Option<String>> = None;
+        if let Some(selected) = SettingsTab::file_selection_dialog(ui, format!("Select a Workrave \"{}\" file", filename).as_str(),
+                                                                   filename) {
+            new_path = Some(Some(selected));
         }
+        if ui.button("Auto-detect").clicked() {
+            *detection = detect::detect(filename);
+            match &detection.found {
+                Some(found) => new_path = Some(Some(found.clone())),
+                None => input.status = PathStatus::Invalid("Not found in any of the default locations".to_string()),
+            }
+        }
+        if ui.button("Clear").clicked() {
+            new_path = Some(None);
+        }
+        if let Some(new_path) = new_path {
+            input.set_text(new_path.clone().unwrap_or_default());
+            changed |= *path != new_path;
+            *path = new_path;
+        }
+        input.status_ui(ui);
+        ui.end_row();
+        changed
     }
 
     fn file_selection_dialog(ui: &mut Ui, dialog_title: &str, file_name: &str) -> Option<String> {
@@ -125,42 +1032,228 @@ impl SettingsTab {
         }
     }
 
-    pub fn ui(&mut self, ui: &mut Ui) {
+    /// Returns true when the settings were changed and the stats need reloading
+    pub fn ui(&mut self, ui: &mut Ui) -> bool {
         let mut have_settings_changed = false;
+        let mut needs_reload = false;
         ui.vertical(|ui| {
-            ui.heading(RichText::new("Workrave"));
-            ui.separator();
-            ui.columns(3, |columns| {
-                columns[0].vertical(|ui| {
-                    ui.label("historystats filepath");
-                    ui.separator();
-                    ui.label("todaystats filepath");
-                });
-                columns[1].vertical(|ui| {
-                    SettingsTab::filepath_ui(ui, &self.settings.workrave_historystats_path);
-                    ui.separator();
-                    SettingsTab::filepath_ui(ui, &self.settings.workrave_todaystats_path);
-                });
-                columns[2].vertical(|ui| {
-                    if let Some(path) = SettingsTab::file_selection_dialog(ui,
-                                                       format!("Select a Workrave \"{}\" file", workrave::WORKRAVE_HISTORYSTATS_FILENAME).as_str(),
-                                                       workrave::WORKRAVE_HISTORYSTATS_FILENAME) {
-                        self.settings.workrave_historystats_path = Some(path);
-                        have_settings_changed = true;
-                    }
-                    ui.separator();
-                    if let Some(path) = SettingsTab::file_selection_dialog(ui,
-                                                                           format!("Select a Workrave \"{}\" file", workrave::WORKRAVE_TODAYSTATS_FILENAME).as_str(),
-                                                                           workrave::WORKRAVE_TODAYSTATS_FILENAME) {
-                        self.settings.workrave_todaystats_path = Some(path);
-                        have_settings_changed = true;
-                    }
-                });
-            });
+            self.status_ui(ui);
+            let (renamed, switched) = self.profiles_ui(ui);
+            // Loaded sessions are labelled with their profile's name
+            needs_reload |= renamed || switched;
+            needs_reload |= self.merged_profiles_ui(ui);
+            needs_reload |= self.source_ui(ui);
+            ui.add_space(20.0);
+            needs_reload |= match self.settings.profile().source {
+                source::SourceKind::Workrave => self.workrave_ui(ui),
+                source::SourceKind::ActivityWatch => self.activitywatch_ui(ui),
+            };
+            ui.add_space(20.0);
+            needs_reload |= self.timezone_ui(ui);
+            have_settings_changed |= self.week_start_ui(ui);
+            ui.add_space(20.0);
+            // Merged profiles' movement is converted to this calibration while loading
+            needs_reload |= self.calibration_ui(ui);
+            ui.add_space(20.0);
+            ui.label(format!("Settings are saved to {}", Settings::settings_path().display()));
         });
 
+        have_settings_changed |= needs_reload;
         if have_settings_changed {
-            self.settings.save_settings().unwrap();
+            self.settings.save_and_report();
         }
+        needs_reload
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_path_in() {
+        let exe_dir = std::env::temp_dir().join(format!("informant-settings-{}", std::process::id()));
+        let config_dir = Path::new("/home/user/.config");
+        fs::create_dir_all(&exe_dir).unwrap();
+
+        assert_eq!(Settings::settings_path_in(Some(&exe_dir), Some(config_dir)),
+                   config_dir.join("informant").join("settings.json"));
+        assert_eq!(Settings::settings_path_in(Some(&exe_dir), None), exe_dir.join("settings.json"));
+
+        fs::write(exe_dir.join(PORTABLE_MARKER_FILENAME), "").unwrap();
+        assert_eq!(Settings::settings_path_in(Some(&exe_dir), Some(config_dir)), exe_dir.join("settings.json"));
+        fs::remove_dir_all(&exe_dir).unwrap();
+    }
+
+    #[test]
+    fn from_json_migrates_unversioned_settings() {
+        let contents = r#"{"workrave_historystats_path":"/home/user/.workrave/historystats","workrave_todaystats_path":null}"#;
+        let settings = Settings::from_json(contents).unwrap();
+
+        assert_eq!(settings.version, SETTINGS_VERSION);
+        assert_eq!(settings.profiles.len(), 1);
+        assert_eq!(settings.profile().name, DEFAULT_PROFILE_NAME);
+        assert_eq!(settings.profile().workrave_historystats_path.as_deref(), Some("/home/user/.workrave/historystats"));
+        assert_eq!(settings.week_start, Weekday::Mon);
+    }
+
+    #[test]
+    fn from_json_makes_unversioned_relative_paths_absolute() {
+        let contents = r#"{"workrave_historystats_path":"stats/historystats","workrave_todaystats_path":""}"#;
+        let settings = Settings::from_json(contents).unwrap();
+        let working_directory = std::env::current_dir().unwrap();
+
+        assert_eq!(settings.profile().workrave_historystats_path,
+                   Some(working_directory.join("stats/historystats").display().to_string()));
+        assert_eq!(settings.profile().workrave_todaystats_path.as_deref(), Some(""));
+
+        // Only version 1 files were kept in the working directory
+        let settings = Settings::from_json(r#"{"version":2,"workrave_historystats_path":"stats/historystats"}"#).unwrap();
+        assert_eq!(settings.profile().workrave_historystats_path.as_deref(), Some("stats/historystats"));
+    }
+
+    #[test]
+    fn from_json_rejects_newer_and_malformed_settings() {
+        assert!(matches!(Settings::from_json(r#"{"version":99}"#), Err(SettingsError::NewerVersion { found: 99 })));
+        assert!(matches!(Settings::from_json("{\"workrave_historystats_path\":"), Err(SettingsError::Json(_))));
+        assert!(matches!(Settings::from_json("[]"), Err(SettingsError::Json(_))));
+    }
+
+    #[test]
+    fn only_v1_settings_are_copied_from_the_working_directory() {
+        assert!(Settings::is_v1_settings(r#"{"workrave_historystats_path":null,"workrave_todaystats_path":null}"#));
+        assert!(!Settings::is_v1_settings(r#"{"theme":"dark"}"#));
+        assert!(!Settings::is_v1_settings("not json"));
+    }
+
+    #[test]
+    fn newer_settings_files_are_kept() {
+        let path = std::env::temp_dir().join(format!("informant-newer-settings-{}.json", std::process::id()));
+        let contents = r#"{"version":99,"profiles":[]}"#;
+        fs::write(&path, contents).unwrap();
+        let mut settings = Settings::from_json(r#"{"version":3,"profiles":[],"active_profile":0}"#).unwrap();
+
+        settings.load_or_replace(&path);
+        assert!(settings.keep_newer_file);
+        assert!(settings.load_warning.is_some());
+        settings.save_and_report();
+        assert_eq!(fs::read_to_string(&path).unwrap(), contents);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn write_atomically_replaces_file() {
+        let dir = std::env::temp_dir().join(format!("informant-atomic-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("settings.json");
+        fs::write(&path, "old").unwrap();
+
+        write_atomically(&path, b"new").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert!(!dir.join("settings.json.tmp").exists());
+
+        // Renaming over a directory fails, the temporary file is cleaned up and the directory is untouched
+        let blocked = dir.join("blocked");
+        fs::create_dir_all(blocked.join("inner")).unwrap();
+        assert!(write_atomically(&blocked, b"new").is_err());
+        assert!(!dir.join("blocked.tmp").exists());
+        assert!(blocked.join("inner").is_dir());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn path_input_status() {
+        let path = std::env::temp_dir().join(format!("informant-path-input-{}", std::process::id()));
+        fs::write(&path, "WorkRaveStats 4\n").unwrap();
+
+        assert_eq!(PathInput::new(&None).status, PathStatus::Empty);
+        assert_eq!(PathInput::new(&Some(path.display().to_string())).status, PathStatus::Valid(workrave::FormatVersion::V4));
+
+        fs::write(&path, "WorkRaveStats 9\n").unwrap();
+        let input = PathInput::new(&Some(path.display().to_string()));
+        assert_eq!(input.status, PathStatus::Invalid("WorkRaveStats version 9 is not supported, expected 3 or 4".to_string()));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn export_path_input_status() {
+        let path = std::env::temp_dir().join(format!("informant-export-input-{}.json", std::process::id()));
+        fs::write(&path, r#"{"buckets": {"aw-watcher-afk_desktop": {"type": "afkstatus", "events": []}}}"#).unwrap();
+        assert_eq!(PathInput::for_export(&Some(path.display().to_string())).status, PathStatus::ValidExport);
+
+        fs::write(&path, "WorkRaveStats 4\n").unwrap();
+        assert!(matches!(PathInput::for_export(&Some(path.display().to_string())).status, PathStatus::Invalid(_)));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn from_json_moves_v2_settings_into_a_profile() {
+        let contents = r#"{"version":2,"workrave_historystats_path":"/data/historystats","workrave_todaystats_path":null,
+                           "timezone":"Europe/London","timezone_overrides":[],"day_start":"04:00:00",
+                           "movement_calibration":{"pixels_per_meter":3000.0},"week_start":"Sun"}"#;
+        let settings = Settings::from_json(contents).unwrap();
+        let profile = settings.profile();
+
+        assert_eq!(profile.workrave_historystats_path.as_deref(), Some("/data/historystats"));
+        assert_eq!(profile.timezone, Some(chrono_tz::Europe::London));
+        assert_eq!(profile.movement_calibration.pixels_per_meter, 3000.0);
+        assert_eq!(settings.day_start, NaiveTime::from_hms_opt(4, 0, 0).unwrap());
+        assert_eq!(settings.week_start, Weekday::Sun);
+    }
+
+    #[test]
+    fn from_json_keeps_active_profile_in_range() {
+        let settings = Settings::from_json(r#"{"version":3,"profiles":[],"active_profile":4}"#).unwrap();
+
+        assert_eq!(settings.profiles, vec![Profile::new(DEFAULT_PROFILE_NAME)]);
+        assert_eq!(settings.active_profile, 0);
+    }
+
+    #[test]
+    fn stats_sources_include_merged_profiles() {
+        let settings = Settings::from_json(r#"{"version":3,"active_profile":0,"profiles":[
+            {"name":"Desktop","workrave_historystats_path":"desktop/historystats","merged_profiles":["Laptop","Work","Gone"]},
+            {"name":"Laptop","workrave_historystats_path":"laptop/historystats","timezone":"Europe/London"},
+            {"name":"Work","source":"ActivityWatch","activitywatch_export_path":"aw-buckets-export.json"}
+        ]}"#).unwrap();
+        let sources = settings.stats_sources();
+
+        assert_eq!(sources.iter().map(|source| source.name.as_str()).collect::<Vec<_>>(), vec!["Desktop", "Laptop", "Work"]);
+        assert_eq!(sources[1].history_path, "laptop/historystats");
+        assert_eq!(sources[1].kind, source::SourceKind::Workrave);
+        assert_eq!((sources[2].kind, sources[2].today_path.as_deref()), (source::SourceKind::ActivityWatch, None));
+        assert_eq!(sources[1].options.timezone.default, Some(chrono_tz::Europe::London));
+    }
+
+    #[test]
+    fn rename_profile_refuses_names_in_use() {
+        let mut settings = Settings::from_json(r#"{"version":3,"active_profile":0,"profiles":[
+            {"name":"Home"},
+            {"name":"Laptop"},
+            {"name":"Desk","merged_profiles":["Home","Laptop"]}
+        ]}"#).unwrap();
+
+        assert!(settings.rename_profile("Laptop").is_err());
+        assert_eq!(settings.rename_profile(" Laptop2 "), Ok(true));
+        assert_eq!(settings.rename_profile("Laptop2"), Ok(false));
+        assert_eq!(settings.profile().name, "Laptop2");
+        assert_eq!(settings.profiles[2].merged_profiles, vec!["Laptop2", "Laptop"]);
+    }
+
+    #[test]
+    fn profile_names_stay_unique() {
+        let mut settings = Settings::from_json(r#"{"version":3,"active_profile":0,"profiles":[
+            {"name":"Profile 3"},
+            {"name":"Profile 3"},
+            {"name":" "}
+        ]}"#).unwrap();
+        let names: Vec<&str> = settings.profiles.iter().map(|profile| profile.name.as_str()).collect();
+        assert_eq!(names, vec!["Profile 3", "Profile 3 2", "Profile"]);
+
+        assert_eq!(settings.new_profile_name(), "Profile 4");
+        settings.profiles.remove(2);
+        assert_eq!(settings.new_profile_name(), "Profile 4");
+        assert!(settings.rename_profile("  ").is_err());
     }
 }
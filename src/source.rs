@@ -0,0 +1,150 @@
+use crate::activitywatch::{ActivityWatchSource, ExportError};
+use crate::cache::HistoryCache;
+use crate::history::{History, LoadOptions};
+use crate::workrave::{self, LoadedHistory, ParseError};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// An activity tracker whose recorded data can be loaded into a `History`, the model `StatsTab` plots.
+/// Sources are loaded from worker and watcher threads.
+pub trait DataSource: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Loads everything recorded in the file at `path`
+    fn load_history(&self, path: &str, options: &LoadOptions) -> Result<Loaded, LoadError>;
+
+    /// Loads a file that only holds the current day, for trackers that keep one apart from their history.
+    /// It is merged over the history with `History::merge_todaystats`.
+    fn load_today(&self, path: &str, options: &LoadOptions) -> Result<Loaded, LoadError>;
+}
+
+/// A history loaded by a `DataSource`, along with the entries it had to skip
+#[derive(Debug)]
+pub struct Loaded {
+    pub history: History,
+    pub warnings: Vec<LoadError>,
+}
+
+/// Why a `DataSource` couldn't load a file, or skipped an entry in it
+#[derive(Debug)]
+pub enum LoadError {
+    Workrave(ParseError),
+    ActivityWatch(ExportError),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Workrave(error) => write!(f, "{}", error),
+            LoadError::ActivityWatch(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl From<ParseError> for LoadError {
+    fn from(error: ParseError) -> Self {
+        LoadError::Workrave(error)
+    }
+}
+
+impl From<ExportError> for LoadError {
+    fn from(error: ExportError) -> Self {
+        LoadError::ActivityWatch(error)
+    }
+}
+
+impl From<LoadedHistory> for Loaded {
+    fn from(loaded: LoadedHistory) -> Self {
+        Loaded {
+            history: loaded.history,
+            warnings: loaded.warnings.into_iter().map(LoadError::from).collect(),
+        }
+    }
+}
+
+/// Which `DataSource` a profile loads its stats with
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum SourceKind {
+    #[default]
+    Workrave,
+    ActivityWatch,
+}
+
+impl SourceKind {
+    pub const ALL: [SourceKind; 2] = [SourceKind::Workrave, SourceKind::ActivityWatch];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SourceKind::Workrave => "Workrave",
+            SourceKind::ActivityWatch => "ActivityWatch",
+        }
+    }
+
+    pub fn data_source(&self) -> Box<dyn DataSource> {
+        match self {
+            SourceKind::Workrave => Box::new(WorkraveSource::new(HistoryCache::in_user_cache_dir())),
+            SourceKind::ActivityWatch => Box::new(ActivityWatchSource),
+        }
+    }
+}
+
+/// Workrave's `historystats` and `todaystats` files
+pub struct WorkraveSource {
+    /// Parsed `historystats` files, so only sessions appended since the last load are parsed
+    cache: Option<HistoryCache>,
+}
+
+impl WorkraveSource {
+    pub fn new(cache: Option<HistoryCache>) -> Self {
+        Self { cache }
+    }
+}
+
+impl DataSource for WorkraveSource {
+    fn name(&self) -> &'static str {
+        "Workrave"
+    }
+
+    /// Reads `historystats` through the cache when there is one, retrying while Workrave is rewriting it
+    fn load_history(&self, path: &str, options: &LoadOptions) -> Result<Loaded, LoadError> {
+        let loaded = History::load_consistently(path, workrave::CONSISTENT_READ_ATTEMPTS, workrave::CONSISTENT_READ_DELAY, || {
+            match &self.cache {
+                Some(cache) => cache.load_historystats(path, options),
+                None => History::load_historystats(path, options),
+            }
+        })?;
+        Ok(loaded.into())
+    }
+
+    fn load_today(&self, path: &str, options: &LoadOptions) -> Result<Loaded, LoadError> {
+        Ok(History::load_todaystats(path, options)?.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn workrave_source_loads_history_and_today() {
+        let dir = std::env::temp_dir().join(format!("informant-source-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("historystats").to_string_lossy().to_string();
+        fs::write(&path, "WorkRaveStats 4\n\
+                          D 7 10 122 9 0 7 10 122 17 0\n\
+                          m 6 3600 100 20 50 30 500 \n").unwrap();
+        let source = WorkraveSource::new(None);
+        let options = LoadOptions::default();
+
+        let history = source.load_history(&path, &options).unwrap();
+        let today = source.load_today(&path, &options).unwrap();
+        assert_eq!(history.history.days.len(), 1);
+        assert_eq!(today.history.days.len(), 1);
+        assert!(history.history.format.is_some());
+        assert!(history.warnings.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,17 +1,115 @@
-use crate::{workrave};
+use crate::{history, settings, source, today, watcher};
 use eframe::egui;
 use egui::*;
 use plot::{Plot, PlotPoint, Legend, Bar, BarChart};
-use chrono::{NaiveDate, Datelike};
+use chrono::{NaiveDate, Datelike, Weekday};
 use std::ops::RangeInclusive;
+use std::sync::mpsc;
+use std::thread;
 
 pub struct StatsTab {
-    pub workrave_history: Option<workrave::WorkraveHistory>,
-    pub current_day: Option<workrave::WorkraveDay>,
+    pub history: Option<history::History>,
+    /// The latest date a todaystats file was recorded on
+    today: Option<NaiveDate>,
     first_history_load: bool,
+    load_error: Option<String>,
+    load_warnings: Vec<String>,
+    granularity: history::Granularity,
+    aggregate: Aggregate,
+    gap_policy: history::GapPolicy,
+    metric: Metric,
+    /// Receives the history from the worker thread while it is loading
+    loading: Option<mpsc::Receiver<HistoryLoad>>,
+    /// One for each source, in the order of `Settings::stats_sources`
+    watchers: Vec<watcher::StatsWatcher>,
+    today_panel: today::TodayPanel,
+    /// Whether bars are split into each source's share when several are merged
+    split_by_source: bool,
+    /// Dropped whenever `history` changes
+    plot_cache: Option<PlotCache>,
+}
+
+/// What the plots are built from, kept between frames until the history or the grouping changes
+struct PlotCache {
+    period: BarPeriod,
+    sources: Vec<String>,
+    missing_dates: Vec<NaiveDate>,
+    rollups: Vec<history::Rollup>,
+    /// Each source's rollups in the order of `sources`, only built once bars are split by source
+    source_rollups: Option<Vec<Vec<history::Rollup>>>,
+}
+
+impl PlotCache {
+    fn new(history: &history::History, period: BarPeriod) -> Self {
+        Self {
+            period,
+            sources: history.sources(),
+            missing_dates: history.missing_dates(),
+            rollups: history.rollup(period.granularity, period.week_start),
+            source_rollups: None,
+        }
+    }
+}
+
+/// The outcome of loading history on a worker thread
+struct HistoryLoad {
+    history: Result<history::History, String>,
+    today: Option<NaiveDate>,
+    warnings: Vec<String>,
+}
+
+/// Colours given to sources in turn when bars are split by source
+const SOURCE_COLORS: [Color32; 6] = [
+    Color32::from_rgb(221, 18, 101),
+    Color32::from_rgb(0, 202, 252),
+    Color32::from_rgb(255, 195, 29),
+    Color32::from_rgb(206, 145, 254),
+    Color32::from_rgb(255, 128, 7),
+    Color32::from_rgb(220, 120, 244),
+];
+
+/// Whether plots show raw totals or rates that ignore how long each day was
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+enum Metric {
+    Totals,
+    Rates,
+}
+
+/// How the days within a week, month or year are combined into one bar
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+enum Aggregate {
+    Total,
+    Mean,
+    Median,
+}
+
+impl Aggregate {
+    fn name(&self) -> &'static str {
+        match self {
+            Aggregate::Total => "Total",
+            Aggregate::Mean => "Daily Mean",
+            Aggregate::Median => "Daily Median",
+        }
+    }
+
+    fn value(&self, rollup: &history::Rollup, field: history::StatsField, gaps: history::GapPolicy) -> f64 {
+        match self {
+            Aggregate::Total => rollup.sum.value(field) as f64,
+            Aggregate::Mean => rollup.mean(field, gaps),
+            Aggregate::Median => rollup.median(field, gaps),
+        }
+    }
+}
+
+/// What a bar covers, used to label it when hovered
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+struct BarPeriod {
+    granularity: history::Granularity,
+    week_start: Weekday,
 }
 
 struct PlotData {
+    headings: [&'static str; 3],
     key_strokes: Vec<BarChart>,
     mouse_movement: Vec<BarChart>,
     activity_time: Vec<BarChart>,
@@ -20,43 +118,330 @@ struct PlotData {
 impl StatsTab {
     pub fn default() -> Self {
         Self {
-            workrave_history: None,
-            current_day: None,
+            history: None,
+            today: None,
             first_history_load: true,
+            load_error: None,
+            load_warnings: vec![],
+            granularity: history::Granularity::Day,
+            aggregate: Aggregate::Total,
+            gap_policy: history::GapPolicy::Exclude,
+            metric: Metric::Totals,
+            loading: None,
+            watchers: vec![],
+            today_panel: today::TodayPanel::default(),
+            split_by_source: false,
+            plot_cache: None,
+        }
+    }
+
+    /// Drops the loaded history so it is loaded again with the current settings on the next frame
+    pub fn request_reload(&mut self) {
+        self.history = None;
+        self.plot_cache = None;
+        self.first_history_load = true;
+        self.loading = None;
+        self.watchers.clear();
+        self.today = None;
+    }
+
+    /// Loads the history on a worker thread, replacing any load that is already running
+    fn start_loading(&mut self, ctx: &Context, settings: &settings::Settings) {
+        let sources = settings.stats_sources();
+        if sources.is_empty() {
+            println!("Failed to load data, no path given");
+            return;
         }
+        let calibration = settings.profile().movement_calibration;
+        self.watch_files(ctx, &sources);
+        let ctx = ctx.clone();
+
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let load = StatsTab::load_sources(&sources, &calibration);
+            // The receiver is gone if a newer load replaced this one
+            if sender.send(load).is_ok() {
+                ctx.request_repaint();
+            }
+        });
+        self.loading = Some(receiver);
     }
 
-    pub fn ui(&mut self, ui: &mut Ui, historystats_path: &Option<String>, todaystats_path: &Option<String>, frame: &eframe::Frame) -> Response {
-        if let Some(path) = historystats_path {
-            if Option::is_none(&self.workrave_history) && self.first_history_load {
-                self.workrave_history = workrave::WorkraveHistory::load_historystats(path);
-                if let Some(today_path) = todaystats_path {
-                    self.workrave_history.as_mut().unwrap().add_todaystats(today_path);
-                    println!("Loaded todaystats");
+    /// Starts watching the stats files, unless they are already watched with the same settings
+    fn watch_files(&mut self, ctx: &Context, sources: &[settings::StatsSource]) {
+        if self.watchers.len() == sources.len() && self.watchers.iter().zip(sources).all(|(watcher, source)| watcher.watches(source)) {
+            return;
+        }
+        self.watchers = sources.iter()
+            .filter_map(|source| match watcher::StatsWatcher::new(ctx.clone(), source) {
+                Ok(watcher) => Some(watcher),
+                Err(error) => {
+                    println!("Failed to watch stats files of {}: {}", source.name, error);
+                    None
                 }
+            })
+            .collect();
+    }
 
-                self.first_history_load = false;
+    /// Reloads the history when Workrave writes historystats, and merges todaystats into it when that changes
+    fn poll_watcher(&mut self, ctx: &Context, settings: &settings::Settings) {
+        let mut reload = false;
+        for watcher in &self.watchers {
+            let source = &watcher.source().name;
+            for event in watcher.events() {
+                match event {
+                    watcher::WatchEvent::HistoryChanged => reload = true,
+                    watcher::WatchEvent::TodayChanged(Ok(mut loaded)) => {
+                        if let Some(history) = &mut self.history {
+                            loaded.history.convert_movement(&watcher.source().calibration, &settings.profile().movement_calibration);
+                            self.today = self.today.max(StatsTab::todaystats_date(&loaded.history));
+                            history.merge_todaystats(loaded.history);
+                            self.plot_cache = None;
+                            let prefix = format!("{} today: ", source);
+                            self.load_warnings.retain(|w| !w.starts_with(&prefix));
+                            self.load_warnings.extend(loaded.warnings.iter().map(|w| format!("{}{}", prefix, w)));
+                        }
+                    }
+                    watcher::WatchEvent::TodayChanged(Err(error)) => println!("Failed to reload the current day of {}: {}", source, error),
+                }
             }
         }
+        // A load that is already running reads the new contents too
+        if reload && self.loading.is_none() {
+            self.start_loading(ctx, settings);
+        }
+    }
 
-        let history = match &self.workrave_history {
-            Some(data) => data,
-            None => {
-                return self.no_history_data_ui(ui, historystats_path);
+    /// Swaps in the loaded history once the worker thread has finished
+    fn poll_loading(&mut self) {
+        let load = match &self.loading {
+            Some(receiver) => match receiver.try_recv() {
+                Ok(load) => load,
+                Err(mpsc::TryRecvError::Empty) => return,
+                Err(mpsc::TryRecvError::Disconnected) => HistoryLoad {
+                    history: Err("Loading stopped unexpectedly".to_string()),
+                    today: None,
+                    warnings: vec![],
+                },
+            },
+            None => return,
+        };
+
+        self.loading = None;
+        self.load_warnings = load.warnings;
+        self.today = load.today;
+        self.plot_cache = None;
+        match load.history {
+            Ok(history) => {
+                self.history = Some(history);
+                self.load_error = None;
+            }
+            Err(error) => {
+                self.history = None;
+                self.load_error = Some(error);
             }
+        }
+    }
+
+    /// Loads every source and merges them into one history, labelling each session with its source
+    /// and converting its movement to `calibration`, the one the plots measure distance with
+    fn load_sources(sources: &[settings::StatsSource], calibration: &history::MovementCalibration) -> HistoryLoad {
+        let mut warnings = vec![];
+        let mut errors = vec![];
+        let mut today_date = None;
+        let mut combined: Option<history::History> = None;
+        for source in sources {
+            // A source that can't be read, e.g. on a share that is offline, is left out of the combined history
+            let (mut history, today) = match StatsTab::load_history(source, &mut warnings) {
+                Ok(loaded) => loaded,
+                Err(error) => {
+                    errors.push(format!("{}: {}", source.name, error));
+                    continue;
+                }
+            };
+            history.set_source(&source.name);
+            history.convert_movement(&source.calibration, calibration);
+            let today = today.map(|mut today| {
+                today.set_source(&source.name);
+                today.convert_movement(&source.calibration, calibration);
+                today
+            });
+            let combined = combined.get_or_insert_with(history::History::default);
+            combined.merge(history);
+            if let Some(today) = today {
+                today_date = today_date.max(StatsTab::todaystats_date(&today));
+                combined.merge_todaystats(today);
+            }
+        }
+        let history = match combined {
+            Some(history) => {
+                warnings.extend(errors.iter().map(|error| format!("{} (left out)", error)));
+                Ok(history)
+            }
+            None if errors.is_empty() => Err("No history path set".to_string()),
+            None => Err(errors.join("\n")),
+        };
+        HistoryLoad { history, today: today_date, warnings }
+    }
+
+    /// Loads one source's history along with its current day, if it keeps one apart
+    fn load_history(source: &settings::StatsSource, warnings: &mut Vec<String>)
+                    -> Result<(history::History, Option<history::History>), source::LoadError> {
+        let data_source = source.kind.data_source();
+        let loaded = data_source.load_history(&source.history_path, &source.options)?;
+        warnings.extend(loaded.warnings.iter().map(|w| format!("{} history: {}", source.name, w)));
+
+        let mut today = None;
+        if let Some(today_path) = &source.today_path {
+            match data_source.load_today(today_path, &source.options) {
+                Ok(loaded) => {
+                    warnings.extend(loaded.warnings.iter().map(|w| format!("{} today: {}", source.name, w)));
+                    today = Some(loaded.history);
+                }
+                Err(error) => warnings.push(format!("{} today: {}", source.name, error)),
+            }
+        }
+        Ok((loaded.history, today))
+    }
+
+    /// The day todaystats was recorded on, it only ever has one
+    fn todaystats_date(today: &history::History) -> Option<NaiveDate> {
+        today.last_day().map(|(date, _)| *date)
+    }
+
+    fn plot_controls_ui(&mut self, ui: &mut Ui, source_count: usize) {
+        ui.horizontal(|ui| {
+            ui.label("Group by");
+            for granularity in [history::Granularity::Day,
+                                history::Granularity::Week,
+                                history::Granularity::Month,
+                                history::Granularity::Year] {
+                ui.selectable_value(&mut self.granularity, granularity, granularity.name());
+            }
+
+            ui.separator();
+            ui.selectable_value(&mut self.metric, Metric::Totals, "Totals");
+            ui.selectable_value(&mut self.metric, Metric::Rates, "Rates")
+                .on_hover_text("Keystrokes per active minute, clicks per active hour, mouse speed and idle time");
+
+            ui.separator();
+            let can_aggregate = self.granularity != history::Granularity::Day && self.metric == Metric::Totals;
+            ui.add_enabled_ui(can_aggregate, |ui| {
+                ui.label("Show");
+                for aggregate in [Aggregate::Total, Aggregate::Mean, Aggregate::Median] {
+                    ui.selectable_value(&mut self.aggregate, aggregate, aggregate.name());
+                }
+            });
+
+            ui.separator();
+            ui.add_enabled_ui(can_aggregate && self.aggregate != Aggregate::Total, |ui| {
+                ui.label("Missing days");
+                ui.selectable_value(&mut self.gap_policy, history::GapPolicy::Exclude, "Excluded");
+                ui.selectable_value(&mut self.gap_policy, history::GapPolicy::CountAsZero, "Count as zero");
+            });
+
+            ui.separator();
+            ui.toggle_value(&mut self.today_panel.open, "Today");
+
+            if source_count > 1 {
+                ui.separator();
+                ui.add_enabled_ui(self.can_split_by_source(), |ui| {
+                    ui.toggle_value(&mut self.split_by_source, "Split by source")
+                        .on_hover_text("Stack each merged profile's share of the keystrokes, movement and active time")
+                        .on_disabled_hover_text("Only totals can be split by source, each source's means and medians don't add up");
+                });
+            }
+
+            if !self.load_warnings.is_empty() {
+                ui.separator();
+                ui.label(RichText::new(format!("{} entries or sources were skipped", self.load_warnings.len()))
+                    .color(Color32::from_rgb(255, 195, 29)))
+                    .on_hover_text(self.load_warnings.join("\n"));
+            }
+        });
+    }
+
+    /// Sources' shares only add up to the combined bar when the bars are totals
+    fn can_split_by_source(&self) -> bool {
+        self.metric == Metric::Totals && (self.granularity == history::Granularity::Day || self.aggregate == Aggregate::Total)
+    }
+
+    /// Rebuilds the plot cache if the grouping changed, and builds each source's rollups once they are needed
+    fn update_plot_cache(&mut self, week_start: Weekday) {
+        let history = match &self.history {
+            Some(history) => history,
+            None => return,
+        };
+        let period = BarPeriod {
+            granularity: self.granularity,
+            week_start,
+        };
+        if !matches!(&self.plot_cache, Some(cache) if cache.period == period) {
+            self.plot_cache = Some(PlotCache::new(history, period));
+        }
+        let split = self.split_by_source && self.can_split_by_source();
+        if let Some(cache) = &mut self.plot_cache {
+            if split && cache.sources.len() > 1 && cache.source_rollups.is_none() {
+                cache.source_rollups = Some(cache.sources.iter()
+                    .map(|source| history.source_rollup(period.granularity, period.week_start, source))
+                    .collect());
+            }
+        }
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui, settings: &settings::Settings, frame: &eframe::Frame) -> Response {
+        self.poll_loading();
+        self.poll_watcher(ui.ctx(), settings);
+        if self.history.is_none() && self.first_history_load && !settings.stats_sources().is_empty() {
+            self.start_loading(ui.ctx(), settings);
+            self.first_history_load = false;
+        }
+        // Reloads triggered by the watcher keep showing the current history until they finish
+        if self.loading.is_some() && self.history.is_none() {
+            return self.loading_ui(ui, settings);
+        }
+
+        if self.history.is_some() {
+            self.update_plot_cache(settings.week_start);
+            let source_count = self.plot_cache.as_ref().map_or(0, |cache| cache.sources.len());
+            self.plot_controls_ui(ui, source_count);
+            // The controls may have changed the grouping
+            self.update_plot_cache(settings.week_start);
+        }
+
+        let (history, cache) = match (&self.history, &self.plot_cache) {
+            (Some(history), Some(cache)) => (history, cache),
+            _ => {
+                return self.no_history_data_ui(ui, settings);
+            }
+        };
+        let current_day = self.today.and_then(|date| history.get(&date));
+        self.today_panel.ui(ui.ctx(), history, current_day, settings, self.gap_policy);
+
+        let aggregate = match self.granularity {
+            history::Granularity::Day => Aggregate::Total,
+            _ => self.aggregate,
         };
 
         ui.vertical_centered(|ui| {
             let link_axis_group = plot::LinkedAxisGroup::new(true, false);
 
-            let window_size = frame.info().window_info.size;
+            let mut window_size = frame.info().window_info.size;
+            window_size.y -= 25.0;
             let large_plot_size = egui::Vec2::new(window_size.x - 15.0,
                                                   (window_size.y - 15.0) * 0.5);
             let small_plot_size = egui::Vec2::new((&large_plot_size.x - 10.0) * 0.5,
                                                   window_size.y * 0.40);
-            let plot_data = StatsTab::build_plot_data(&history);
+            let calibration = &settings.profile().movement_calibration;
+            let plot_data = match (self.metric, &cache.source_rollups) {
+                (Metric::Totals, Some(source_rollups)) if self.split_by_source && self.can_split_by_source() => {
+                    StatsTab::build_source_plot_data(cache, source_rollups, calibration)
+                }
+                (Metric::Totals, _) => StatsTab::build_plot_data(cache, calibration, aggregate, self.gap_policy),
+                (Metric::Rates, _) => StatsTab::build_rate_plot_data(cache, calibration),
+            };
 
-            ui.heading("Keystrokes");
+            ui.heading(plot_data.headings[0]);
             let mut keystrokes_plot = Plot::new("keystrokes_plot")
                 .width(large_plot_size.x)
                 .height(large_plot_size.y)
@@ -72,10 +457,10 @@ impl StatsTab {
             ui.vertical(|ui| {
                 ui.columns(2, |columns| {
                     columns[0].vertical_centered(|ui| {
-                        ui.heading("Mouse Movement");
+                        ui.heading(plot_data.headings[1]);
                     });
                     columns[1].vertical_centered(|ui| {
-                        ui.heading("Activity Time");
+                        ui.heading(plot_data.headings[2]);
                     });
                 });
 
@@ -106,41 +491,79 @@ impl StatsTab {
         }).response
     }
 
-    fn no_history_data_ui(&mut self, ui: &mut Ui, path: &Option<String>) -> Response {
+    fn no_history_data_ui(&mut self, ui: &mut Ui, settings: &settings::Settings) -> Response {
         ui.vertical_centered(|ui| {
             ui.heading("\n\n\n\n\n\n\n\n\n\n\n\n\n\n\nNo history data loaded");
+            if let Some(error) = &self.load_error {
+                ui.label(RichText::new(error).color(Color32::from_rgb(221, 18, 101)));
+            }
             if ui.button("Try load data").clicked() {
-                match path {
-                    Some(s) => {
-                        self.workrave_history = workrave::WorkraveHistory::load_historystats(s);
-                    },
-                    None => println!("Failed to load data, no path given"),
-                };
+                self.start_loading(ui.ctx(), settings);
             }
         }).response
     }
 
-    fn build_plot_data(history: &workrave::WorkraveHistory) -> PlotData {
-        fn create_bar_chart(bars: Vec<Bar>, name: &str, color: Color32, stacked_on: Option<&BarChart>, y_is_time: bool) -> BarChart {
-            let chart = match y_is_time {
-                true => {
-                    BarChart::new(bars).name(name).color(color).element_formatter(Box::new(StatsTab::active_time_element_formatter))
-                },
-                false => {
-                    BarChart::new(bars).name(name).color(color).element_formatter(Box::new(StatsTab::box_chart_element_formatter))
-                }
-            };
+    fn loading_ui(&self, ui: &mut Ui, settings: &settings::Settings) -> Response {
+        ui.vertical_centered(|ui| {
+            ui.heading("\n\n\n\n\n\n\n\n\n\n\n\n\n\n\nLoading history data");
+            for source in settings.stats_sources() {
+                ui.label(source.history_path);
+            }
+            ui.spinner();
+        }).response
+    }
 
-            match stacked_on {
-                Some(s) => {
-                    chart.stack_on(&[s])
-                }
-                None => {
-                    chart
-                }
+    fn create_bar_chart(bars: Vec<Bar>, name: &str, color: Color32, stacked_on: Option<&BarChart>, y_is_time: bool, period: BarPeriod) -> BarChart {
+        let chart = match y_is_time {
+            true => {
+                BarChart::new(bars).name(name).color(color).element_formatter(Box::new(move |bar, _| StatsTab::active_time_element_formatter(bar, period)))
+            },
+            false => {
+                BarChart::new(bars).name(name).color(color).element_formatter(Box::new(move |bar, _| StatsTab::box_chart_element_formatter(bar, period)))
+            }
+        };
+
+        match stacked_on {
+            Some(s) => {
+                chart.stack_on(&[s])
+            }
+            None => {
+                chart
             }
         }
+    }
+
+    /// Greyed out full height bars marking the days with no recorded data
+    fn create_gap_chart(missing_dates: &[NaiveDate], height: f64) -> BarChart {
+        let bars = missing_dates.iter()
+            .map(|date| Bar::new(date.num_days_from_ce() as f64, height).width(1.0).name("No data"))
+            .collect();
+        BarChart::new(bars)
+            .name("No data")
+            .color(Color32::from_rgba_unmultiplied(128, 128, 128, 40))
+            .element_formatter(Box::new(StatsTab::gap_element_formatter))
+    }
+
+    /// Height of the tallest stack of bars, each slice holds one chart's bars in the same order
+    fn stacked_height(charts: &[&[Bar]]) -> f64 {
+        let bar_count = charts.iter().map(|bars| bars.len()).max().unwrap_or(0);
+        (0..bar_count)
+            .map(|i| charts.iter().filter_map(|bars| bars.get(i)).map(|bar| bar.value).sum::<f64>())
+            .fold(0.0, f64::max)
+    }
+
+    /// Adds the gap chart behind the data charts, scaled to the tallest bar
+    fn with_gap_chart(charts: Vec<BarChart>, missing_dates: &[NaiveDate], height: f64) -> Vec<BarChart> {
+        if missing_dates.is_empty() {
+            return charts;
+        }
+        let mut with_gaps = vec![StatsTab::create_gap_chart(missing_dates, height)];
+        with_gaps.extend(charts);
+        with_gaps
+    }
 
+    fn build_plot_data(cache: &PlotCache, calibration: &history::MovementCalibration, aggregate: Aggregate, gaps: history::GapPolicy) -> PlotData {
+        let period = cache.period;
         let mut total_keystrokes: Vec<Bar> = vec![];
         let mut total_mouse_clicks: Vec<Bar> = vec![];
         let mut total_movement: Vec<Bar> = vec![];
@@ -148,68 +571,186 @@ impl StatsTab {
         let mut total_active_time: Vec<Bar> = vec![];
         let mut total_mouse_time: Vec<Bar> = vec![];
 
-        let sorted_dates = StatsTab::get_sorted_dates(history);
-        for date in sorted_dates {
-            match history.days.get(date) {
-                Some(day) => {
-                    let x = date.num_days_from_ce() as f64;
-                    let stats = &day.stats;
-
-                    total_keystrokes.push(Bar::new(x, stats.total_keystrokes as f64).name("Keystrokes"));
-                    total_mouse_clicks.push(Bar::new(x, stats.total_mouse_clicks as f64).name("Mouse Clicks"));
-                    total_movement.push(Bar::new(x, stats.total_mouse_movement as f64).name("Movement"));
-                    total_click_movement.push(Bar::new(x, stats.total_mouse_click_movement as f64).name("Click Movement"));
-                    total_active_time.push(Bar::new(x, stats.total_active_time_seconds as f64).name("Active Time"));
-                    total_mouse_time.push(Bar::new(x, stats.total_mouse_movement_time as f64).name("Active Mouse Time"));
-                }
-                None => continue
-            }
+        for rollup in &cache.rollups {
+            // Bars are centred on their period and span it
+            let start = rollup.start.num_days_from_ce() as f64;
+            let end = rollup.end.num_days_from_ce() as f64;
+            let x = (start + end) / 2.0;
+            let width = (end - start + 1.0) * 0.95;
+            let bar = |value: f64, name: &str| Bar::new(x, value).width(width).name(name);
+            let value = |field: history::StatsField| aggregate.value(rollup, field, gaps);
+
+            total_keystrokes.push(bar(value(history::StatsField::Keystrokes), "Keystrokes"));
+            total_mouse_clicks.push(bar(value(history::StatsField::Clicks), "Mouse Clicks"));
+            total_movement.push(bar(calibration.meters(value(history::StatsField::MouseMovement)), "Movement"));
+            total_click_movement.push(bar(calibration.meters(value(history::StatsField::ClickMovement)), "Click Movement"));
+            total_active_time.push(bar(value(history::StatsField::ActiveTime), "Active Time"));
+            total_mouse_time.push(bar(value(history::StatsField::MovementTime), "Active Mouse Time"));
         };
 
-        let mouse_clicks_chart = create_bar_chart(total_mouse_clicks,
+        let missing_dates = &cache.missing_dates;
+        let key_strokes_height = StatsTab::stacked_height(&[&total_mouse_clicks, &total_keystrokes]);
+        let mouse_movement_height = StatsTab::stacked_height(&[&total_movement, &total_click_movement]);
+        let activity_time_height = StatsTab::stacked_height(&[&total_active_time, &total_mouse_time]);
+
+        let mouse_clicks_chart = StatsTab::create_bar_chart(total_mouse_clicks,
                                                   "Mouse Clicks",
                                                   Color32::from_rgb(0, 202, 252),
                                                   None,
-                                                  false);
-        let keystrokes_chart = create_bar_chart(total_keystrokes,
+                                                  false,
+                                                  period);
+        let keystrokes_chart = StatsTab::create_bar_chart(total_keystrokes,
                                                 "Keystrokes",
                                                 Color32::from_rgb(221, 18, 101),
                                                 Some(&mouse_clicks_chart),
-                                                false);
+                                                false,
+                                                period);
 
-        let movement_chart = create_bar_chart(total_movement,
+        let movement_chart = StatsTab::create_bar_chart(total_movement,
                                               "Movement",
                                               Color32::from_rgb(206, 145, 254),
                                               None,
-                                              false);
-        let click_movement_chart = create_bar_chart(total_click_movement,
+                                              false,
+                                              period);
+        let click_movement_chart = StatsTab::create_bar_chart(total_click_movement,
                                                     "Click Movement",
                                                     Color32::from_rgb(255, 195, 29),
                                                     Some(&movement_chart),
-                                                    false);
+                                                    false,
+                                                  period);
 
-        let active_time_chart = create_bar_chart(total_active_time,
+        let active_time_chart = StatsTab::create_bar_chart(total_active_time,
                                                  "Active Time",
                                                  Color32::from_rgb(220, 120, 244),
                                                  None,
-                                                 true);
-        let mouse_time_chart = create_bar_chart(total_mouse_time,
+                                                 true,
+                                                 period);
+        let mouse_time_chart = StatsTab::create_bar_chart(total_mouse_time,
                                                 "Mouse Time",
                                                 Color32::from_rgb(255, 128, 7),
                                                 Some(&active_time_chart),
-                                                true);
+                                                true,
+                                                period);
 
         PlotData {
-            key_strokes: vec![mouse_clicks_chart, keystrokes_chart],
-            mouse_movement: vec![movement_chart, click_movement_chart],
-            activity_time: vec![active_time_chart, mouse_time_chart],
+            headings: ["Keystrokes", "Mouse Movement", "Activity Time"],
+            key_strokes: StatsTab::with_gap_chart(vec![mouse_clicks_chart, keystrokes_chart], missing_dates, key_strokes_height),
+            mouse_movement: StatsTab::with_gap_chart(vec![movement_chart, click_movement_chart], missing_dates, mouse_movement_height),
+            activity_time: StatsTab::with_gap_chart(vec![active_time_chart, mouse_time_chart], missing_dates, activity_time_height),
         }
     }
 
-    fn get_sorted_dates(history: &workrave::WorkraveHistory) -> Vec<&NaiveDate> {
-        let mut sorted_dates: Vec<&NaiveDate> = history.days.keys().clone().collect();
-        sorted_dates.sort();
-        sorted_dates
+    /// Total keystrokes, movement and active time with each source's share stacked on the ones before it
+    fn build_source_plot_data(cache: &PlotCache, source_rollups: &[Vec<history::Rollup>], calibration: &history::MovementCalibration) -> PlotData {
+        let (period, sources) = (cache.period, &cache.sources);
+        let mut keystrokes: Vec<Vec<Bar>> = vec![];
+        let mut movement: Vec<Vec<Bar>> = vec![];
+        let mut active_time: Vec<Vec<Bar>> = vec![];
+
+        for (source, rollups) in sources.iter().zip(source_rollups) {
+            let mut source_keystrokes = vec![];
+            let mut source_movement = vec![];
+            let mut source_active_time = vec![];
+            for rollup in rollups {
+                let start = rollup.start.num_days_from_ce() as f64;
+                let end = rollup.end.num_days_from_ce() as f64;
+                let x = (start + end) / 2.0;
+                let width = (end - start + 1.0) * 0.95;
+                let bar = |value: f64| Bar::new(x, value).width(width).name(source);
+                let value = |field: history::StatsField| rollup.sum.value(field) as f64;
+
+                source_keystrokes.push(bar(value(history::StatsField::Keystrokes)));
+                source_movement.push(bar(calibration.meters(value(history::StatsField::MouseMovement))));
+                source_active_time.push(bar(value(history::StatsField::ActiveTime)));
+            }
+            keystrokes.push(source_keystrokes);
+            movement.push(source_movement);
+            active_time.push(source_active_time);
+        }
+
+        let missing_dates = &cache.missing_dates;
+        let height = |bars: &[Vec<Bar>]| StatsTab::stacked_height(&bars.iter().map(Vec::as_slice).collect::<Vec<_>>());
+        let (keystrokes_height, movement_height, active_time_height) = (height(&keystrokes), height(&movement), height(&active_time));
+        let stack = |bars: Vec<Vec<Bar>>, y_is_time: bool| {
+            let mut charts: Vec<BarChart> = vec![];
+            for (i, (bars, source)) in bars.into_iter().zip(sources).enumerate() {
+                let below: Vec<&BarChart> = charts.iter().collect();
+                let chart = StatsTab::create_bar_chart(bars,
+                                                       source,
+                                                       SOURCE_COLORS[i % SOURCE_COLORS.len()],
+                                                       None,
+                                                       y_is_time,
+                                                       period)
+                    .stack_on(&below);
+                charts.push(chart);
+            }
+            charts
+        };
+
+        PlotData {
+            headings: ["Keystrokes by Source", "Mouse Movement by Source", "Activity Time by Source"],
+            key_strokes: StatsTab::with_gap_chart(stack(keystrokes, false), missing_dates, keystrokes_height),
+            mouse_movement: StatsTab::with_gap_chart(stack(movement, false), missing_dates, movement_height),
+            activity_time: StatsTab::with_gap_chart(stack(active_time, true), missing_dates, active_time_height),
+        }
+    }
+
+    fn build_rate_plot_data(cache: &PlotCache, calibration: &history::MovementCalibration) -> PlotData {
+        let period = cache.period;
+        let mut keystroke_rate: Vec<Bar> = vec![];
+        let mut click_rate: Vec<Bar> = vec![];
+        let mut mouse_speed: Vec<Bar> = vec![];
+        let mut idle_percentage: Vec<Bar> = vec![];
+
+        for rollup in &cache.rollups {
+            let start = rollup.start.num_days_from_ce() as f64;
+            let end = rollup.end.num_days_from_ce() as f64;
+            let x = (start + end) / 2.0;
+            let width = (end - start + 1.0) * 0.95;
+            let rates = rollup.rates();
+
+            // Keystroke and click rates have different scales so they sit side by side instead of stacking
+            keystroke_rate.push(Bar::new(x - width / 4.0, rates.keystrokes_per_active_minute).width(width / 2.0).name("Keystrokes per Minute"));
+            click_rate.push(Bar::new(x + width / 4.0, rates.clicks_per_active_hour).width(width / 2.0).name("Clicks per Hour"));
+            mouse_speed.push(Bar::new(x, calibration.meters(rates.mouse_speed)).width(width).name("Mouse Speed (m/s)"));
+            idle_percentage.push(Bar::new(x, rates.idle_ratio * 100.0).width(width).name("Idle %"));
+        }
+
+        let missing_dates = &cache.missing_dates;
+        let rate_height = StatsTab::stacked_height(&[&keystroke_rate]).max(StatsTab::stacked_height(&[&click_rate]));
+        let speed_height = StatsTab::stacked_height(&[&mouse_speed]);
+
+        let keystroke_rate_chart = StatsTab::create_bar_chart(keystroke_rate,
+                                                              "Keystrokes per Active Minute",
+                                                              Color32::from_rgb(221, 18, 101),
+                                                              None,
+                                                              false,
+                                                              period);
+        let click_rate_chart = StatsTab::create_bar_chart(click_rate,
+                                                          "Clicks per Active Hour",
+                                                          Color32::from_rgb(0, 202, 252),
+                                                          None,
+                                                          false,
+                                                          period);
+        let mouse_speed_chart = StatsTab::create_bar_chart(mouse_speed,
+                                                           "Mouse Speed (m/s)",
+                                                           Color32::from_rgb(206, 145, 254),
+                                                           None,
+                                                           false,
+                                                           period);
+        let idle_chart = StatsTab::create_bar_chart(idle_percentage,
+                                                    "Idle %",
+                                                    Color32::from_rgb(220, 120, 244),
+                                                    None,
+                                                    false,
+                                                    period);
+
+        PlotData {
+            headings: ["Typing & Clicking Rate", "Mouse Speed", "Idle Time"],
+            key_strokes: StatsTab::with_gap_chart(vec![keystroke_rate_chart, click_rate_chart], missing_dates, rate_height),
+            mouse_movement: StatsTab::with_gap_chart(vec![mouse_speed_chart], missing_dates, speed_height),
+            activity_time: StatsTab::with_gap_chart(vec![idle_chart], missing_dates, 100.0),
+        }
     }
 
     fn configure_plot_settings(plot: Plot) -> Plot {
@@ -225,7 +766,7 @@ impl StatsTab {
         let date = match NaiveDate::from_num_days_from_ce_opt(plot_point.x as i32) {
             Some(value) => value,
             None => {
-                return format!("DATE ERR");
+                return "DATE ERR".to_string();
             }
         };
 
@@ -240,32 +781,47 @@ impl StatsTab {
         }
     }
 
-    fn box_chart_element_formatter(bar: &Bar, _chart: &BarChart) -> String {
-        let date = match NaiveDate::from_num_days_from_ce_opt(bar.argument as i32) {
+    /// Describes the period a bar covers, bars are centred so any day within the period works
+    fn period_to_string(x: f64, period: BarPeriod) -> Option<String> {
+        let date = NaiveDate::from_num_days_from_ce_opt(x.round() as i32)?;
+        let start = period.granularity.period_start(date, period.week_start);
+        Some(match period.granularity {
+            history::Granularity::Day => "Date:     ".to_owned() + &StatsTab::naive_date_to_string(&start),
+            history::Granularity::Week => "Week of:  ".to_owned() + &StatsTab::naive_date_to_string(&start),
+            history::Granularity::Month => format!("Month:    {}-{}", start.month(), start.year()),
+            history::Granularity::Year => format!("Year:     {}", start.year()),
+        })
+    }
+
+    fn gap_element_formatter(bar: &Bar, _chart: &BarChart) -> String {
+        match NaiveDate::from_num_days_from_ce_opt(bar.argument.round() as i32) {
+            Some(date) => format!("No data recorded\nDate:     {}", StatsTab::naive_date_to_string(&date)),
+            None => "DATE ERR".to_string(),
+        }
+    }
+
+    fn box_chart_element_formatter(bar: &Bar, period: BarPeriod) -> String {
+        let date = match StatsTab::period_to_string(bar.argument, period) {
             Some(value) => value,
             None => {
-                return format!("DATE ERR");
+                return "DATE ERR".to_string();
             }
         };
 
-        let date = "Date:     ".to_owned() + &StatsTab::naive_date_to_string(&date);
         format!("{}\n{}\n{:.2}", bar.name, date, bar.value)
     }
 
-    fn active_time_element_formatter(bar: &Bar, _chart: &BarChart) -> String {
-        match NaiveDate::from_num_days_from_ce_opt(bar.argument as i32) {
+    fn active_time_element_formatter(bar: &Bar, period: BarPeriod) -> String {
+        match StatsTab::period_to_string(bar.argument, period) {
             Some(date) => {
-                let date = "Date:     ".to_owned() + &StatsTab::naive_date_to_string(&date);
-                format!("{}\n{}\nValue:    {}hr {}min {}s",
+                format!("{}\n{}\nValue:    {:.0}hr {:.0}min {:.0}s",
                         bar.name,
                         date,
-                        format!("{:.0}", (bar.value / 60.0) / 60.0),
-                        format!("{:.0}", (bar.value / 60.0) % 60.0),
-                        format!("{:.0}", bar.value % 60.0))
+                        (bar.value / 60.0) / 60.0,
+                        (bar.value / 60.0) % 60.0,
+                        bar.value % 60.0)
             },
-            None => {
-                return format!("DATE ERR");
-            }
+            None => "DATE ERR".to_string()
         }
     }
 
@@ -273,13 +829,13 @@ impl StatsTab {
         let date = match NaiveDate::from_num_days_from_ce_opt(x as i32) {
             Some(value) => value,
             None => {
-                return format!("DATE ERR");
+                return "DATE ERR".to_string();
             }
         };
         StatsTab::naive_date_to_string(&date)
     }
 
-    fn naive_date_to_string(date: &NaiveDate) -> String {
+    pub(crate) fn naive_date_to_string(date: &NaiveDate) -> String {
         format!("{:02}-{}-{}",
                 date.day(),
                 date.month(),
@@ -318,4 +874,84 @@ mod tests {
         Date:     03-12-2022\n\
         Value:    27");
     }
+
+    #[test]
+    fn period_to_string_week() {
+        let period = BarPeriod {
+            granularity: history::Granularity::Week,
+            week_start: Weekday::Mon,
+        };
+        // 03-12-2022 is a Saturday
+        assert_eq!(StatsTab::period_to_string(738492.0, period).unwrap(), "Week of:  28-11-2022");
+    }
+
+    #[test]
+    fn stacked_height() {
+        let lower = vec![Bar::new(1.0, 5.0), Bar::new(2.0, 1.0)];
+        let upper = vec![Bar::new(1.0, 2.0), Bar::new(2.0, 9.0)];
+        assert_eq!(StatsTab::stacked_height(&[&lower, &upper]), 10.0);
+        assert_eq!(StatsTab::stacked_height(&[]), 0.0);
+    }
+
+    fn stats_source(name: &str, history_path: &str) -> settings::StatsSource {
+        settings::StatsSource {
+            name: name.to_string(),
+            kind: source::SourceKind::Workrave,
+            history_path: history_path.to_string(),
+            today_path: None,
+            options: history::LoadOptions::default(),
+            calibration: history::MovementCalibration::default(),
+        }
+    }
+
+    #[test]
+    fn load_sources_leaves_out_unreadable_sources() {
+        let dir = std::env::temp_dir().join(format!("informant-stats-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("historystats").to_string_lossy().to_string();
+        std::fs::write(&path, "WorkRaveStats 4\n\
+                               D 7 10 122 9 0 7 10 122 17 0\n\
+                               m 6 3600 100 20 50 30 500 \n").unwrap();
+        let missing = dir.join("missing").to_string_lossy().to_string();
+
+        let load = StatsTab::load_sources(&[stats_source("Desktop", &path), stats_source("Laptop", &missing)], &Default::default());
+        let history = load.history.unwrap();
+        assert_eq!(history.sources(), vec!["Desktop"]);
+        assert!(matches!(&load.warnings[..], [warning] if warning.starts_with("Laptop: ")));
+
+        let load = StatsTab::load_sources(&[stats_source("Laptop", &missing)], &Default::default());
+        assert!(load.history.unwrap_err().starts_with("Laptop: "));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn plot_cache_follows_the_grouping() {
+        let load = |content: &str, source: &str| {
+            let mut history = history::History::load_historystats_reader(content.as_bytes(), &history::LoadOptions::default())
+                .unwrap().history;
+            history.set_source(source);
+            history
+        };
+        let mut history = load("WorkRaveStats 4\n\
+                                D 7 10 122 9 0 7 10 122 17 0\n\
+                                m 6 3600 100 20 50 30 500 \n", "Desktop");
+        history.merge(load("WorkRaveStats 4\n\
+                            D 8 10 122 9 0 8 10 122 17 0\n\
+                            m 6 1800 100 20 50 30 200 \n", "Laptop"));
+        let mut tab = StatsTab::default();
+        tab.history = Some(history);
+
+        tab.update_plot_cache(Weekday::Mon);
+        let cache = tab.plot_cache.as_ref().unwrap();
+        assert_eq!(cache.rollups.len(), 2);
+        assert!(cache.source_rollups.is_none());
+
+        tab.split_by_source = true;
+        tab.granularity = history::Granularity::Week;
+        tab.update_plot_cache(Weekday::Mon);
+        let cache = tab.plot_cache.as_ref().unwrap();
+        assert_eq!(cache.period.granularity, history::Granularity::Week);
+        assert_eq!(cache.rollups.len(), 1);
+        assert_eq!(cache.source_rollups.as_ref().unwrap().len(), 2);
+    }
 }
@@ -0,0 +1,136 @@
+use crate::{history, settings, stats};
+use eframe::egui;
+use egui::*;
+use history::StatsField;
+
+const DEFAULT_TRAILING_DAYS: u64 = 28;
+
+/// A window comparing the day in todaystats with the days before it
+pub struct TodayPanel {
+    pub open: bool,
+    trailing_days: u64,
+}
+
+/// A row of the Today panel
+struct TodayRow {
+    name: &'static str,
+    field: StatsField,
+}
+
+const ROWS: [TodayRow; 4] = [
+    TodayRow { name: "Keystrokes", field: StatsField::Keystrokes },
+    TodayRow { name: "Mouse clicks", field: StatsField::Clicks },
+    TodayRow { name: "Mouse movement", field: StatsField::MouseMovement },
+    TodayRow { name: "Active time", field: StatsField::ActiveTime },
+];
+
+impl TodayPanel {
+    pub fn default() -> Self {
+        Self {
+            open: true,
+            trailing_days: DEFAULT_TRAILING_DAYS,
+        }
+    }
+
+    pub fn ui(&mut self, ctx: &Context, history: &history::History, current_day: Option<&history::Day>,
+              settings: &settings::Settings, gaps: history::GapPolicy) {
+        let mut open = self.open;
+        Window::new("Today")
+            .open(&mut open)
+            .anchor(Align2::RIGHT_TOP, [-20.0, 80.0])
+            .resizable(false)
+            .show(ctx, |ui| {
+                self.contents_ui(ui, history, current_day, settings, gaps);
+            });
+        self.open = open;
+    }
+
+    fn contents_ui(&mut self, ui: &mut Ui, history: &history::History, current_day: Option<&history::Day>,
+                   settings: &settings::Settings, gaps: history::GapPolicy) {
+        let day = match current_day {
+            Some(day) => day,
+            None => {
+                ui.label("No todaystats loaded, set its path in Settings");
+                return;
+            }
+        };
+
+        let options = settings.load_options();
+        let today = options.today();
+        let recorded_on = options.workday(&day.datetime_range.start());
+        if recorded_on != today {
+            ui.label(RichText::new(format!("todaystats is from {}, Workrave may not be running",
+                                           stats::StatsTab::naive_date_to_string(&recorded_on)))
+                .color(Color32::from_rgb(255, 195, 29)));
+        }
+
+        ui.horizontal(|ui| {
+            ui.label("Compared with the last");
+            ui.add(DragValue::new(&mut self.trailing_days).clamp_range(1..=365));
+            ui.label("days");
+        });
+
+        let trailing = history.trailing_rollup(today, self.trailing_days);
+        Grid::new("today_grid").striped(true).show(ui, |ui| {
+            ui.label("");
+            ui.strong("Today");
+            ui.strong("Average");
+            ui.strong("Difference");
+            ui.end_row();
+
+            for row in ROWS {
+                let value = day.stats.value(row.field) as f64;
+                let average = trailing.mean(row.field, gaps);
+                ui.label(row.name);
+                ui.label(TodayPanel::value_to_string(row.field, value, &settings.profile().movement_calibration));
+                ui.label(TodayPanel::value_to_string(row.field, average, &settings.profile().movement_calibration));
+                match TodayPanel::difference(value, average) {
+                    Some(difference) => ui.label(format!("{:+.0}%", difference)),
+                    None => ui.label("-"),
+                };
+                ui.end_row();
+            }
+        });
+    }
+
+    /// How far `value` is above or below `average`, as a percentage of it
+    fn difference(value: f64, average: f64) -> Option<f64> {
+        match average > 0.0 {
+            true => Some((value / average - 1.0) * 100.0),
+            false => None,
+        }
+    }
+
+    fn value_to_string(field: StatsField, value: f64, calibration: &history::MovementCalibration) -> String {
+        match field {
+            StatsField::MouseMovement | StatsField::ClickMovement => format!("{:.1}m", calibration.meters(value)),
+            StatsField::ActiveTime | StatsField::MovementTime => TodayPanel::seconds_to_string(value),
+            StatsField::Clicks | StatsField::Keystrokes => format!("{:.0}", value),
+        }
+    }
+
+    fn seconds_to_string(seconds: f64) -> String {
+        let minutes = (seconds / 60.0).round() as u64;
+        format!("{}hr {}min", minutes / 60, minutes % 60)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seconds_to_string() {
+        assert_eq!(TodayPanel::seconds_to_string(0.0), "0hr 0min");
+        assert_eq!(TodayPanel::seconds_to_string(6150.0), "1hr 43min");
+        assert_eq!(TodayPanel::seconds_to_string(3599.0), "1hr 0min");
+    }
+
+    #[test]
+    fn difference() {
+        assert_eq!(TodayPanel::difference(150.0, 100.0), Some(50.0));
+        assert_eq!(TodayPanel::difference(50.0, 100.0), Some(-50.0));
+        assert_eq!(TodayPanel::difference(50.0, 0.0), None);
+    }
+}
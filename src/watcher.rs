@@ -0,0 +1,107 @@
+use crate::settings::StatsSource;
+use crate::source::{LoadError, Loaded};
+use eframe::egui;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+
+/// A change to one of the watched stats files
+pub enum WatchEvent {
+    HistoryChanged,
+    /// The current day's file is small, so it is read again straight away on the watcher's thread.
+    /// Its sessions are labelled with the watched source.
+    TodayChanged(Result<Loaded, LoadError>),
+}
+
+/// Watches the files of one source for changes made by its tracker while the app is open
+pub struct StatsWatcher {
+    _watcher: RecommendedWatcher,
+    source: StatsSource,
+    receiver: mpsc::Receiver<WatchEvent>,
+}
+
+impl StatsWatcher {
+    pub fn new(ctx: egui::Context, source: &StatsSource) -> notify::Result<Self> {
+        let history_file = StatsWatcher::normalize(&source.history_path);
+        let today_file = source.today_path.as_deref().map(StatsWatcher::normalize);
+
+        let (sender, receiver) = mpsc::channel();
+        let data_source = source.kind.data_source();
+        let today_path = source.today_path.clone();
+        let today_options = source.options.clone();
+        let today_source = source.name.clone();
+        let mut watcher = notify::recommended_watcher(move |result: notify::Result<notify::Event>| {
+            let event = match result {
+                Ok(event) => event,
+                Err(error) => {
+                    println!("Error watching stats files: {}", error);
+                    return;
+                }
+            };
+            if matches!(event.kind, EventKind::Access(_)) {
+                return;
+            }
+
+            let changed = |file: &Path| event.paths.iter().any(|path| path == file);
+            let mut sent = false;
+            if changed(&history_file) {
+                sent |= sender.send(WatchEvent::HistoryChanged).is_ok();
+            }
+            if let (Some(file), Some(path)) = (&today_file, &today_path) {
+                if changed(file) {
+                    let loaded = data_source.load_today(path, &today_options).map(|mut loaded| {
+                        loaded.history.set_source(&today_source);
+                        loaded
+                    });
+                    sent |= sender.send(WatchEvent::TodayChanged(loaded)).is_ok();
+                }
+            }
+            if sent {
+                ctx.request_repaint();
+            }
+        })?;
+
+        // Trackers may replace the files rather than write to them, so their directories are watched
+        let directories: BTreeSet<PathBuf> = std::iter::once(source.history_path.as_str())
+            .chain(source.today_path.as_deref())
+            .filter_map(|path| StatsWatcher::normalize(path).parent().map(Path::to_path_buf))
+            .collect();
+        for directory in directories {
+            watcher.watch(&directory, RecursiveMode::NonRecursive)?;
+        }
+
+        Ok(Self {
+            _watcher: watcher,
+            source: source.clone(),
+            receiver,
+        })
+    }
+
+    /// Whether this watcher was created for this source
+    pub fn watches(&self, source: &StatsSource) -> bool {
+        self.source == *source
+    }
+
+    pub fn source(&self) -> &StatsSource {
+        &self.source
+    }
+
+    /// Changes seen since the last call, in the order they happened
+    pub fn events(&self) -> Vec<WatchEvent> {
+        self.receiver.try_iter().collect()
+    }
+
+    /// The path events for `path` are reported with, the file itself may not exist while it is replaced
+    fn normalize(path: &str) -> PathBuf {
+        let path = Path::new(path);
+        match (path.parent(), path.file_name()) {
+            (Some(parent), Some(file_name)) => {
+                let parent = if parent.as_os_str().is_empty() { Path::new(".") } else { parent };
+                fs::canonicalize(parent).unwrap_or_else(|_| parent.to_path_buf()).join(file_name)
+            }
+            _ => path.to_path_buf(),
+        }
+    }
+}
@@ -1,195 +1,604 @@
+use crate::history::{BreakStats, BreakType, DatetimeRange, Day, History, InputStats, LoadOptions, Session, StatsField, TimezoneConfig};
 use chrono::prelude::*;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
+use std::fmt;
 use std::fs;
-use std::io::{BufReader, BufRead};
+use std::io;
+use std::io::{BufReader, BufRead, BufWriter, Write};
+use std::str::FromStr;
+use std::thread;
 
 pub const WORKRAVE_HISTORYSTATS_FILENAME: &str = "historystats";
 pub const WORKRAVE_TODAYSTATS_FILENAME: &str = "todaystats";
-pub const WORKRAVE_MOVEMENT_TO_METERS: f32 = 4288.0;
+/// How many times to read a stats file that is changing before giving up on a consistent read
+pub const CONSISTENT_READ_ATTEMPTS: u32 = 5;
+pub const CONSISTENT_READ_DELAY: std::time::Duration = std::time::Duration::from_millis(200);
 
-#[derive(Debug)]
-pub struct WorkraveDay {
-    pub datetime_range: DatetimeRange,
-    pub stats: InputStats,
+pub const WORKRAVE_STATS_HEADER: &str = "WorkRaveStats";
+
+/// The historystats format version, taken from the "WorkRaveStats <version>" header line
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Copy, Clone)]
+pub enum FormatVersion {
+    V3,
+    V4,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Copy, Clone)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+}
+
+/// How a stats file was laid out, so a history can be written back the same way
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Copy, Clone)]
+pub struct StatsFormat {
+    pub version: FormatVersion,
+    pub line_ending: LineEnding,
+}
+
+impl Default for StatsFormat {
+    fn default() -> Self {
+        Self {
+            version: FormatVersion::V4,
+            line_ending: LineEnding::Lf,
+        }
+    }
+}
+
+impl LineEnding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+        }
+    }
+}
+
+impl FormatVersion {
+    pub fn from_header(header: &str) -> Result<FormatVersion, ParseError> {
+        let mut parts = header.split_whitespace();
+        if parts.next() != Some(WORKRAVE_STATS_HEADER) {
+            return Err(ParseError::InvalidHeader { found: header.to_string() });
+        }
+        match parts.next() {
+            Some("3") => Ok(FormatVersion::V3),
+            Some("4") => Ok(FormatVersion::V4),
+            Some(version) => Err(ParseError::UnsupportedVersion { found: version.to_string() }),
+            None => Err(ParseError::InvalidHeader { found: header.to_string() }),
+        }
+    }
+
+    pub fn number(&self) -> u32 {
+        match self {
+            FormatVersion::V3 => 3,
+            FormatVersion::V4 => 4,
+        }
+    }
+
+    pub fn header(&self) -> String {
+        format!("{} {}", WORKRAVE_STATS_HEADER, self.number())
+    }
+
+    /// Version 3 "m" lines end after the click count, they have no keystroke field
+    fn stats_layout(&self) -> &'static [StatsField] {
+        match self {
+            FormatVersion::V3 => &[StatsField::ActiveTime,
+                                   StatsField::MouseMovement,
+                                   StatsField::ClickMovement,
+                                   StatsField::MovementTime,
+                                   StatsField::Clicks],
+            FormatVersion::V4 => &[StatsField::ActiveTime,
+                                   StatsField::MouseMovement,
+                                   StatsField::ClickMovement,
+                                   StatsField::MovementTime,
+                                   StatsField::Clicks,
+                                   StatsField::Keystrokes],
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ParseError {
+    /// Never stored with a loaded history, so it is left out of the cache
+    #[serde(skip)]
+    Io(io::Error),
+    InvalidHeader { found: String },
+    UnsupportedVersion { found: String },
+    InvalidNumber { line: usize, value: String },
+    MissingField { line: usize, index: usize },
+    InvalidDate { line: usize },
+    UnknownBreakType { line: usize, id: u64 },
+    UnexpectedLine { line: usize, content: String },
+    IncompleteBlock { line: usize },
 }
 
-#[derive(PartialEq, Debug, Copy, Clone)]
-pub struct DatetimeRange {
-    start: DateTime<Local>,
-    end: DateTime<Local>,
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Io(error) => write!(f, "Failed to read file: {}", error),
+            ParseError::InvalidHeader { found } => write!(f, "Not a WorkRaveStats file, header was \"{}\"", found),
+            ParseError::UnsupportedVersion { found } => write!(f, "WorkRaveStats version {} is not supported, expected 3 or 4", found),
+            ParseError::InvalidNumber { line, value } => write!(f, "Line {}: \"{}\" is not a valid number", line, value),
+            ParseError::MissingField { line, index } => write!(f, "Line {}: missing field {}", line, index),
+            ParseError::InvalidDate { line } => write!(f, "Line {}: date does not exist", line),
+            ParseError::UnknownBreakType { line, id } => write!(f, "Line {}: unknown break type {}", line, id),
+            ParseError::UnexpectedLine { line, content } => write!(f, "Line {}: unexpected line \"{}\"", line, content),
+            ParseError::IncompleteBlock { line } => write!(f, "Line {}: day has no stats line", line),
+        }
+    }
 }
 
-#[derive(PartialEq, Debug, Copy, Clone)]
-pub struct InputStats {
-    pub total_active_time_seconds: u64,
-    pub total_mouse_movement: f32,
-    pub total_mouse_click_movement: f32,
-    pub total_mouse_movement_time: u64,
-    pub total_mouse_clicks: u64,
-    pub total_keystrokes: u64,
+impl std::error::Error for ParseError {}
+
+impl From<io::Error> for ParseError {
+    fn from(error: io::Error) -> Self {
+        ParseError::Io(error)
+    }
 }
 
-impl WorkraveDay {
-    fn round(x: f32, places: u32) -> f32 {
-        let power = 10_i32.pow(places);
-        (x * power as f32).round() / power as f32
+impl BreakStats {
+    /// Number of values Workrave writes on each "B" line
+    pub const VALUE_COUNT: usize = 7;
+
+    fn values(&self) -> [u64; BreakStats::VALUE_COUNT] {
+        [self.prompted,
+         self.taken,
+         self.natural_taken,
+         self.skipped,
+         self.postponed,
+         self.unique_breaks,
+         self.total_overdue_seconds]
     }
+}
 
-    fn convert_date_line(line: &str) -> DatetimeRange {
-        let line: &str = &line[1..];
-        let split_parsed: Vec<u32> = line.trim().split(" ").map(|s| s.parse().unwrap()).collect();
+impl Day {
+    /// Splits a line into numbers, ignoring the line identifier character
+    fn parse_fields<T: FromStr>(line: &str, line_number: usize) -> Result<Vec<T>, ParseError> {
+        line[1..].split_whitespace()
+            .map(|s| s.parse().map_err(|_| ParseError::InvalidNumber {
+                line: line_number,
+                value: s.to_string(),
+            }))
+            .collect()
+    }
 
-        // Month is indexed from 0
-        let start = Local.with_ymd_and_hms(split_parsed[2] as i32 + 1900,
-                                           split_parsed[1] + 1,
-                                           split_parsed[0],
-                                           split_parsed[3],
-                                           split_parsed[4],
-                                           0).unwrap();
-        let end = Local.with_ymd_and_hms(split_parsed[7] as i32 + 1900,
-                                         split_parsed[6] + 1,
-                                         split_parsed[5],
-                                         split_parsed[8],
-                                         split_parsed[9],
-                                         0).unwrap();
-        DatetimeRange {
-            start,
-            end,
+    fn field<T: Copy>(fields: &[T], index: usize, line_number: usize) -> Result<T, ParseError> {
+        fields.get(index).copied().ok_or(ParseError::MissingField {
+            line: line_number,
+            index,
+        })
+    }
+
+    fn convert_date_line(line: &str, line_number: usize, timezone: &TimezoneConfig) -> Result<DatetimeRange, ParseError> {
+        let split_parsed: Vec<u32> = Day::parse_fields(line, line_number)?;
+        let field = |index: usize| Day::field(&split_parsed, index, line_number);
+        let datetime = |offset: usize| -> Result<NaiveDateTime, ParseError> {
+            // Month is indexed from 0, out of range values must not overflow
+            let (hour, minute) = (field(offset + 3)?, field(offset + 4)?);
+            let year = i32::try_from(field(offset + 2)?).ok().and_then(|year| year.checked_add(1900));
+            let month = field(offset + 1)?.checked_add(1);
+            let day = field(offset)?;
+            year.zip(month)
+                .and_then(|(year, month)| NaiveDate::from_ymd_opt(year, month, day))
+                .and_then(|date| date.and_hms_opt(hour, minute, 0))
+                .ok_or(ParseError::InvalidDate { line: line_number })
+        };
+
+        Ok(DatetimeRange::new(timezone.resolve(datetime(0)?), timezone.resolve(datetime(5)?)))
+    }
+
+    fn convert_stats_line(line: &str, line_number: usize, version: FormatVersion) -> Result<InputStats, ParseError> {
+        let split_parsed: Vec<u64> = Day::parse_fields(line, line_number)?;
+        let mut stats = InputStats::default();
+
+        // The first value is the field count, the layout of the rest depends on the format version
+        for (index, field) in version.stats_layout().iter().enumerate() {
+            let value = Day::field(&split_parsed, index + 1, line_number)?;
+            match field {
+                StatsField::ActiveTime => stats.total_active_time_seconds = value,
+                StatsField::MouseMovement => stats.total_mouse_movement = value,
+                StatsField::ClickMovement => stats.total_mouse_click_movement = value,
+                StatsField::MovementTime => stats.total_mouse_movement_time = value,
+                StatsField::Clicks => stats.total_mouse_clicks = value,
+                StatsField::Keystrokes => stats.total_keystrokes = value,
+            }
         }
+        Ok(stats)
     }
 
-    fn convert_stats_line(line: &str) -> InputStats {
-        // Ignore the 'm' line identifier character
-        let line: &str = &line[1..];
-        let split_parsed: Vec<u64> = line.trim().split(" ").map(|s| s.parse().unwrap()).collect();
-        InputStats {
-            total_active_time_seconds: split_parsed[1],
-            total_mouse_movement: WorkraveDay::round(split_parsed[2] as f32 / WORKRAVE_MOVEMENT_TO_METERS, 2),
-            total_mouse_click_movement: WorkraveDay::round(split_parsed[3] as f32 / WORKRAVE_MOVEMENT_TO_METERS, 2),
-            total_mouse_movement_time: split_parsed[4],
-            total_mouse_clicks: split_parsed[5],
-            total_keystrokes: split_parsed[6],
+    fn convert_break_line(line: &str, line_number: usize) -> Result<(BreakType, BreakStats), ParseError> {
+        let split_parsed: Vec<u64> = Day::parse_fields(line, line_number)?;
+        let id = Day::field(&split_parsed, 0, line_number)?;
+        let break_type = BreakType::from_id(id).ok_or(ParseError::UnknownBreakType {
+            line: line_number,
+            id,
+        })?;
+
+        // Workrave writes the number of values after the break type, missing values count as zero
+        let value_count = Day::field(&split_parsed, 1, line_number)? as usize;
+        let values: Vec<u64> = split_parsed[2..].iter().take(value_count).copied().collect();
+        let value = |index: usize| values.get(index).copied().unwrap_or(0);
+        Ok((break_type, BreakStats {
+            prompted: value(0),
+            taken: value(1),
+            natural_taken: value(2),
+            skipped: value(3),
+            postponed: value(4),
+            unique_breaks: value(5),
+            total_overdue_seconds: value(6),
+        }))
+    }
+}
+
+impl Session {
+    /// Formats a timestamp the way "D" lines store it, with a 0-indexed month and years since 1900
+    fn format_date_fields(datetime: &DateTime<FixedOffset>) -> String {
+        format!("{} {} {} {} {}",
+                datetime.day(),
+                datetime.month0(),
+                datetime.year() - 1900,
+                datetime.hour(),
+                datetime.minute())
+    }
+
+    fn format_stats_line(&self, version: FormatVersion) -> String {
+        let layout = version.stats_layout();
+        let mut line = format!("m {} ", layout.len());
+        for field in layout {
+            line.push_str(&format!("{} ", self.stats.value(*field)));
         }
+        line
     }
 
-    fn build_day(stats: InputStats, dates: DatetimeRange) -> WorkraveDay {
-        WorkraveDay {
-            datetime_range: dates,
-            stats,
+    /// Writes the "D", "B" and "m" lines for this session, matching Workrave's own spacing
+    pub fn write_lines<W: Write>(&self, writer: &mut W, version: FormatVersion, line_ending: LineEnding) -> io::Result<()> {
+        let newline = line_ending.as_str();
+        write!(writer, "D {} {}{}",
+               Session::format_date_fields(&self.datetime_range.start()),
+               Session::format_date_fields(&self.datetime_range.end()),
+               newline)?;
+        for (break_type, break_stats) in &self.breaks {
+            write!(writer, "B {} {} ", break_type.id(), BreakStats::VALUE_COUNT)?;
+            for value in break_stats.values() {
+                write!(writer, "{} ", value)?;
+            }
+            write!(writer, "{}", newline)?;
         }
+        write!(writer, "{}{}", self.format_stats_line(version), newline)
     }
 }
 
+/// A "D" line and the "B" lines that follow it, waiting for the "m" line that completes the session
+struct PendingDay {
+    line_number: usize,
+    datetime_range: Option<DatetimeRange>,
+    breaks: BTreeMap<BreakType, BreakStats>,
+}
+
+/// A loaded history along with any malformed entries that were skipped while parsing it
 #[derive(Debug)]
-pub struct WorkraveHistory {
-    pub days: HashMap<NaiveDate, WorkraveDay>,
+pub struct LoadedHistory {
+    pub history: History,
+    pub warnings: Vec<ParseError>,
+    /// Where the last complete session ends, so sessions appended to the file can be parsed from there.
+    /// `None` if a session was completed by a last line with no line break, which may still be being written.
+    pub resume_at: Option<ParsePosition>,
+    /// How many of the `warnings` come from after `resume_at`, e.g. a day with no stats line at the end of the file
+    pub unfinished_warnings: usize,
+}
+
+/// A point between two lines of a stats file
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Copy, Clone)]
+pub struct ParsePosition {
+    pub bytes: u64,
+    /// The number of the line just before this point
+    pub line: usize,
 }
 
-impl WorkraveHistory {
+impl History {
     pub fn is_file_valid(path: &str) -> bool {
-        match fs::File::open(&path) {
-            Ok(file) => {
-                match BufReader::new(file).lines().next() {
-                    Some(line_result) => {
-                        match line_result {
-                            Ok(line) => {
-                                if line == "WorkRaveStats 4" {
-                                    true
-                                } else {
-                                    false
-                                }
-                            }
-                            Err(_) => false,
-                        }
-                    }
-                    None => false
-                }
-            }
-            Err(_) => {
-                println!("Failed to open file, defaulting to invalid");
+        match History::detect_version(path) {
+            Ok(_) => true,
+            Err(error) => {
+                println!("{} is not a valid stats file: {}", path, error);
                 false
             }
         }
     }
 
-    pub fn load_historystats(path: &str) -> Option<WorkraveHistory> {
-        if !WorkraveHistory::is_file_valid(&path) {
-            return None
-        };
+    pub fn detect_version(path: &str) -> Result<FormatVersion, ParseError> {
+        let file = fs::File::open(path)?;
+        let header = BufReader::new(file).lines().next().transpose()?.unwrap_or_default();
+        FormatVersion::from_header(header.trim())
+    }
+
+    pub fn load_historystats(path: &str, options: &LoadOptions) -> Result<LoadedHistory, ParseError> {
+        let file = fs::File::open(path)?;
+        History::load_historystats_file(&file, options)
+    }
 
-        let valid_file = match fs::File::open(path) {
-            Ok(file) => file,
-            Err(_) => panic!("Failed to open historystats")
+    pub fn load_historystats_file(file: &fs::File, options: &LoadOptions) -> Result<LoadedHistory, ParseError> {
+        History::load_historystats_reader(BufReader::new(file), options)
+    }
+
+    /// Parses historystats content, skipping any session that contains a malformed line.
+    /// Only an unreadable source or a missing header fails the whole load.
+    pub fn load_historystats_reader<R: BufRead>(mut reader: R, options: &LoadOptions) -> Result<LoadedHistory, ParseError> {
+        let mut header = String::new();
+        reader.read_line(&mut header)?;
+        let line_ending = if header.ends_with("\r\n") { LineEnding::CrLf } else { LineEnding::Lf };
+        let version = FormatVersion::from_header(header.trim())?;
+
+        let mut history = History {
+            format: Some(StatsFormat { version, line_ending }),
+            days: BTreeMap::new(),
         };
+        let after_header = ParsePosition {
+            bytes: header.len() as u64,
+            line: 1,
+        };
+        let (warnings, resume_at, unfinished_warnings) = history.parse_sessions(reader, options, after_header)?;
 
-        Some(WorkraveHistory::load_historystats_file(&valid_file))
+        Ok(LoadedHistory {
+            history,
+            warnings,
+            resume_at,
+            unfinished_warnings,
+        })
     }
 
-    pub fn load_historystats_file(file: &fs::File) -> WorkraveHistory {
-        let reader = BufReader::new(file);
+    /// Parses the sessions that follow `from`, which must be at the start of a line.
+    /// Returns the skipped entries, where parsing can later resume as described on `LoadedHistory::resume_at`,
+    /// and how many of the skipped entries come after that point.
+    pub(crate) fn parse_sessions<R: BufRead>(&mut self, mut reader: R, options: &LoadOptions, from: ParsePosition)
+                                             -> Result<(Vec<ParseError>, Option<ParsePosition>, usize), ParseError> {
+        let version = self.format.unwrap_or_default().version;
+        let mut warnings: Vec<ParseError> = vec![];
+        let mut pending: Option<PendingDay> = None;
+        let mut position = from;
+        let mut resume_at = Some(from);
+        let mut resume_warnings = 0;
+        let mut buffer = String::new();
 
-        let mut dates: Vec<DatetimeRange> = vec![];
-        let mut input_stats: Vec<InputStats> = vec![];
+        loop {
+            buffer.clear();
+            let read = reader.read_line(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            position.bytes += read as u64;
+            position.line += 1;
+            let ended_cleanly = buffer.ends_with('\n');
 
-        for (i, line) in reader.lines().enumerate() {
-            let line = match line {
-                Ok(text) => text,
-                Err(_) => continue,
-            };
+            let line_number = position.line;
+            let line = buffer.trim();
+            if line.is_empty() {
+                continue;
+            }
 
-            let line = line.trim();
             if line.starts_with("D ") {
-                dates.push(WorkraveDay::convert_date_line(&line));
+                if let Some(unfinished) = pending.take() {
+                    if unfinished.datetime_range.is_some() {
+                        warnings.push(ParseError::IncompleteBlock { line: unfinished.line_number });
+                    }
+                }
+                // A day whose date fails to parse is kept as pending with no date, so its
+                // following lines are skipped along with it
+                let datetime_range = match Day::convert_date_line(line, line_number, &options.timezone) {
+                    Ok(range) => Some(range),
+                    Err(error) => {
+                        warnings.push(error);
+                        None
+                    }
+                };
+                pending = Some(PendingDay {
+                    line_number,
+                    datetime_range,
+                    breaks: BTreeMap::new(),
+                });
             } else if line.starts_with("B ") {
-                continue;
+                match pending.as_mut() {
+                    Some(day) if day.datetime_range.is_some() => {
+                        match Day::convert_break_line(line, line_number) {
+                            Ok((break_type, break_stats)) => {
+                                // A repeated break type replaces the earlier line
+                                day.breaks.insert(break_type, break_stats);
+                            }
+                            Err(error @ ParseError::UnknownBreakType { .. }) => warnings.push(error),
+                            Err(error) => {
+                                warnings.push(error);
+                                day.datetime_range = None;
+                            }
+                        }
+                    }
+                    Some(_) => continue,
+                    None => warnings.push(ParseError::UnexpectedLine {
+                        line: line_number,
+                        content: line.to_string(),
+                    }),
+                }
             } else if line.starts_with("m ") {
-                input_stats.push(WorkraveDay::convert_stats_line(&line));
+                match pending.take() {
+                    Some(PendingDay { datetime_range: Some(date), breaks, .. }) => {
+                        match Day::convert_stats_line(line, line_number, version) {
+                            Ok(stats) => {
+                                if !ended_cleanly {
+                                    // Parsing again from any earlier point would add this session twice
+                                    resume_at = None;
+                                }
+                                History::insert_session(&mut self.days, options, Session {
+                                    datetime_range: date,
+                                    stats,
+                                    breaks,
+                                    source: None,
+                                });
+                            }
+                            Err(error) => warnings.push(error),
+                        }
+                    }
+                    Some(_) => continue,
+                    None => warnings.push(ParseError::UnexpectedLine {
+                        line: line_number,
+                        content: line.to_string(),
+                    }),
+                }
             } else {
-                println!("Line {} ignored, contained: {}", i, line)
+                warnings.push(ParseError::UnexpectedLine {
+                    line: line_number,
+                    content: line.to_string(),
+                });
+            }
+
+            if ended_cleanly && pending.is_none() && resume_at.is_some() {
+                resume_at = Some(position);
+                resume_warnings = warnings.len();
             }
         }
 
-        if dates.len() != input_stats.len() {
-            panic!("historystats file is unreadable, date and stat lines are not equal");
+        if let Some(PendingDay { line_number, datetime_range: Some(_), .. }) = pending {
+            warnings.push(ParseError::IncompleteBlock { line: line_number });
         }
 
-        let mut days: HashMap<NaiveDate, WorkraveDay> = HashMap::new();
-        for (i, date) in dates.iter().enumerate() {
-            days.insert(date.start.date_naive(), WorkraveDay::build_day(input_stats[i], *date));
+        let unfinished_warnings = if resume_at.is_some() { warnings.len() - resume_warnings } else { 0 };
+        Ok((warnings, resume_at, unfinished_warnings))
+    }
+
+    /// Writes the history in the historystats format. The output is normalised rather than an exact copy
+    /// of the loaded file: sessions are written in date order and "B" lines in break type order, each
+    /// "B" line has all of its values with missing ones as zero, only the last "B" line of a break type
+    /// in a session is kept, and times in a daylight saving gap are written as the time they resolved to.
+    /// A history without a `format` is written as version 4 with LF line endings.
+    pub fn write_historystats<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let format = self.format.unwrap_or_default();
+        write!(writer, "{}{}", format.version.header(), format.line_ending.as_str())?;
+
+        for session in self.days.values().flat_map(|day| &day.sessions) {
+            session.write_lines(writer, format.version, format.line_ending)?;
         }
-        WorkraveHistory {
-            days
+        Ok(())
+    }
+
+    pub fn save_historystats(&self, path: &str) -> io::Result<()> {
+        let mut writer = BufWriter::new(fs::File::create(path)?);
+        self.write_historystats(&mut writer)?;
+        writer.flush()
+    }
+
+    /// Runs `load` on a stats file that Workrave may be rewriting, retrying until the file has the
+    /// same size and modification time before and after the read. A file that is unchanged but ends
+    /// partway through a session is accepted, it was not caught mid-write.
+    /// The result of the last attempt is returned whether or not it was consistent.
+    pub fn load_consistently<F>(path: &str, attempts: u32, delay: std::time::Duration, mut load: F) -> Result<LoadedHistory, ParseError>
+    where
+        F: FnMut() -> Result<LoadedHistory, ParseError>,
+    {
+        let file_state = || fs::metadata(path).and_then(|metadata| Ok((metadata.len(), metadata.modified()?))).ok();
+        let mut attempt = 1;
+        loop {
+            let before = file_state();
+            let loaded = load();
+            if (before.is_some() && before == file_state()) || attempt >= attempts {
+                return loaded;
+            }
+            attempt += 1;
+            thread::sleep(delay);
         }
     }
 
-    pub fn add_todaystats(&mut self, path: &str) {
-        match WorkraveHistory::load_historystats(path) {
-            Some(stats) => {
-                self.days.extend(stats.days);
-            },
-            None => {
-                println!("Failed to load todaystats")
+    /// Loads todaystats, retrying while Workrave is rewriting it
+    pub fn load_todaystats(path: &str, options: &LoadOptions) -> Result<LoadedHistory, ParseError> {
+        History::load_consistently(path, CONSISTENT_READ_ATTEMPTS, CONSISTENT_READ_DELAY, || {
+            History::load_historystats(path, options)
+        })
+    }
+
+    /// Merges todaystats into this history, returning any entries that were skipped
+    pub fn add_todaystats(&mut self, path: &str, options: &LoadOptions) -> Result<Vec<ParseError>, ParseError> {
+        let loaded = History::load_todaystats(path, options)?;
+        self.merge_todaystats(loaded.history);
+        Ok(loaded.warnings)
+    }
+
+    /// Replaces the sessions from todaystats' source that start at or after its first session with the
+    /// ones it has now. Earlier sessions, e.g. from historystats before a `day_start` after midnight, and
+    /// sessions from other sources are left in place.
+    pub fn merge_todaystats(&mut self, today: History) {
+        for (date, day) in today.days {
+            let source = day.sessions.first().and_then(|session| session.source.clone());
+            let first_start = day.datetime_range.start();
+            if let Entry::Occupied(mut existing) = self.days.entry(date) {
+                let kept = existing.get().sessions.iter()
+                    .filter(|session| session.source != source || session.datetime_range.start() < first_start)
+                    .cloned()
+                    .collect();
+                match Day::from_sessions(kept) {
+                    Some(kept) => *existing.get_mut() = kept,
+                    None => {
+                        existing.remove();
+                    }
+                }
+            }
+            for session in day.sessions {
+                History::insert_session_on(&mut self.days, date, session);
             }
         }
     }
 }
 
-
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::history::{ActivityRates, GapPolicy, Granularity, MovementCalibration, TimezoneOverride};
 
     #[test]
     fn test_convert_date_line() {
         let line = "D 8 10 122 22 39 8 10 122 22 44";
         let datetime_start = Local.with_ymd_and_hms(2022, 11, 8, 22, 39, 0).unwrap();
         let datetime_end = Local.with_ymd_and_hms(2022, 11, 8, 22, 44, 0).unwrap();
-        let date_range = DatetimeRange {
-            start: datetime_start,
-            end: datetime_end,
-        };
+        let date_range = DatetimeRange::new(datetime_start.fixed_offset(), datetime_end.fixed_offset());
+
+        assert_eq!(Day::convert_date_line(line, 1, &TimezoneConfig::default()).unwrap(), date_range);
+    }
+
+    fn london() -> TimezoneConfig {
+        TimezoneConfig {
+            default: Some(chrono_tz::Europe::London),
+            overrides: vec![],
+        }
+    }
+
+    #[test]
+    fn test_convert_date_line_dst_gap() {
+        // 01:30 on 27-03-2022 does not exist in London, clocks jumped from 01:00 GMT to 02:00 BST
+        let range = Day::convert_date_line("D 27 2 122 1 30 27 2 122 3 0", 1, &london()).unwrap();
+
+        assert_eq!(range.start().to_rfc3339(), "2022-03-27T02:30:00+01:00");
+        assert_eq!(range.end().to_rfc3339(), "2022-03-27T03:00:00+01:00");
+    }
+
+    #[test]
+    fn test_convert_date_line_dst_ambiguous() {
+        // 01:30 on 30-10-2022 happened twice in London, the first (BST) one is used
+        let range = Day::convert_date_line("D 30 9 122 1 30 30 9 122 3 0", 1, &london()).unwrap();
+
+        assert_eq!(range.start().to_rfc3339(), "2022-10-30T01:30:00+01:00");
+        assert_eq!(range.end().to_rfc3339(), "2022-10-30T03:00:00+00:00");
+    }
+
+    #[test]
+    fn test_timezone_override() {
+        let mut timezone = london();
+        timezone.overrides.push(TimezoneOverride {
+            start: NaiveDate::from_ymd_opt(2022, 11, 1).unwrap(),
+            end: NaiveDate::from_ymd_opt(2022, 11, 14).unwrap(),
+            timezone: chrono_tz::Asia::Tokyo,
+        });
+        let range = Day::convert_date_line("D 8 10 122 22 39 15 10 122 9 5", 1, &timezone).unwrap();
 
-        assert_eq!(WorkraveDay::convert_date_line(&line), date_range);
+        assert_eq!(range.start().to_rfc3339(), "2022-11-08T22:39:00+09:00");
+        assert_eq!(range.end().to_rfc3339(), "2022-11-15T09:05:00+00:00");
     }
 
     #[test]
@@ -197,13 +606,459 @@ mod tests {
         let line = "m 6 338 28584 40231 29 104 33 ";
         let stats = InputStats {
             total_active_time_seconds: 338,
-            total_mouse_movement: 6.67,
-            total_mouse_click_movement: 9.38,
+            total_mouse_movement: 28584,
+            total_mouse_click_movement: 40231,
             total_mouse_movement_time: 29,
             total_mouse_clicks: 104,
             total_keystrokes: 33,
         };
 
-        assert_eq!(WorkraveDay::convert_stats_line(&line), stats);
+        assert_eq!(Day::convert_stats_line(line, 1, FormatVersion::V4).unwrap(), stats);
+    }
+
+    #[test]
+    fn test_convert_stats_line_v3() {
+        let line = "m 5 338 28584 40231 29 104";
+        let stats = Day::convert_stats_line(line, 1, FormatVersion::V3).unwrap();
+
+        assert_eq!(stats.total_mouse_clicks, 104);
+        assert_eq!(stats.total_keystrokes, 0);
+    }
+
+    #[test]
+    fn test_format_version_from_header() {
+        assert_eq!(FormatVersion::from_header("WorkRaveStats 3").unwrap(), FormatVersion::V3);
+        assert_eq!(FormatVersion::from_header("WorkRaveStats 4").unwrap(), FormatVersion::V4);
+        assert!(matches!(FormatVersion::from_header("WorkRaveStats 5"),
+                         Err(ParseError::UnsupportedVersion { .. })));
+        assert!(matches!(FormatVersion::from_header("SomethingElse 4"),
+                         Err(ParseError::InvalidHeader { .. })));
+    }
+
+    #[test]
+    fn test_convert_break_line() {
+        let line = "B 1 7 4 2 1 1 3 2 95 ";
+        let stats = BreakStats {
+            prompted: 4,
+            taken: 2,
+            natural_taken: 1,
+            skipped: 1,
+            postponed: 3,
+            unique_breaks: 2,
+            total_overdue_seconds: 95,
+        };
+
+        assert_eq!(Day::convert_break_line(line, 1).unwrap(), (BreakType::RestBreak, stats));
+    }
+
+    #[test]
+    fn test_convert_break_line_unknown_type() {
+        assert!(matches!(Day::convert_break_line("B 7 7 0 0 0 0 0 0 0", 4),
+                         Err(ParseError::UnknownBreakType { line: 4, id: 7 })));
+    }
+
+    #[test]
+    fn test_convert_stats_line_truncated() {
+        assert!(matches!(Day::convert_stats_line("m 6 338 28584", 3, FormatVersion::V4),
+                         Err(ParseError::MissingField { line: 3, index: 3 })));
+    }
+
+    #[test]
+    fn test_convert_date_line_invalid_number() {
+        assert!(matches!(Day::convert_date_line("D 8 10 1x2 22 39 8 10 122 22 44", 2, &TimezoneConfig::default()),
+                         Err(ParseError::InvalidNumber { line: 2, .. })));
+    }
+
+    #[test]
+    fn test_convert_date_line_out_of_range() {
+        for line in ["D 8 4294967295 122 22 39 8 10 122 22 44", "D 8 10 2147483647 22 39 8 10 122 22 44"] {
+            assert!(matches!(Day::convert_date_line(line, 3, &TimezoneConfig::default()),
+                             Err(ParseError::InvalidDate { line: 3 })));
+        }
+    }
+
+    #[test]
+    fn test_load_historystats_invalid_header() {
+        let content = "WorkRaveStats\nD 8 10 122 22 39 8 10 122 22 44\n";
+        assert!(matches!(History::load_historystats_reader(content.as_bytes(), &LoadOptions::default()),
+                         Err(ParseError::InvalidHeader { .. })));
+    }
+
+    #[test]
+    fn test_load_historystats_skips_malformed_block() {
+        let content = "WorkRaveStats 4\n\
+                       D 7 10 122 9 0 7 10 122 17 0\n\
+                       B 0 7 3 2 1 0 0 0 0\n\
+                       m 6 338 28584 40231\n\
+                       D 8 10 122 22 39 8 10 122 22 44\n\
+                       B 0 7 3 2 1 0 0 0 0\n\
+                       m 6 338 28584 40231 29 104 33\n\
+                       D 9 10 122 9 0 9 10 122 17 0\n";
+        let loaded = History::load_historystats_reader(content.as_bytes(), &LoadOptions::default()).unwrap();
+
+        assert_eq!(loaded.history.days.len(), 1);
+        let day = loaded.history.days.get(&NaiveDate::from_ymd_opt(2022, 11, 8).unwrap()).unwrap();
+        assert_eq!(day.stats.total_keystrokes, 33);
+        assert_eq!(day.breaks.get(&BreakType::MicroBreak).unwrap().prompted, 3);
+
+        assert_eq!(loaded.warnings.len(), 2);
+        assert!(matches!(loaded.warnings[0], ParseError::MissingField { line: 4, .. }));
+        assert!(matches!(loaded.warnings[1], ParseError::IncompleteBlock { line: 8 }));
+    }
+
+    fn round_trip(content: &str) -> String {
+        let loaded = History::load_historystats_reader(content.as_bytes(), &LoadOptions::default()).unwrap();
+        assert!(loaded.warnings.is_empty());
+
+        let mut output: Vec<u8> = vec![];
+        loaded.history.write_historystats(&mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_write_historystats_round_trip() {
+        let content = "WorkRaveStats 4\n\
+                       D 7 10 122 9 2 7 10 122 17 31\n\
+                       B 0 7 31 12 9 10 6 18 412 \n\
+                       B 1 7 4 2 1 1 3 2 95 \n\
+                       B 2 7 0 0 0 0 0 0 0 \n\
+                       m 6 20511 802395 398113 6120 4571 30012 \n\
+                       D 8 10 122 22 39 8 10 122 22 44\n\
+                       B 0 7 3 2 1 0 0 0 0 \n\
+                       B 1 7 0 0 0 0 0 0 0 \n\
+                       B 2 7 0 0 0 0 0 0 0 \n\
+                       m 6 338 28584 40231 29 104 33 \n";
+
+        assert_eq!(round_trip(content), content);
+    }
+
+    #[test]
+    fn test_write_historystats_normalises() {
+        let content = "WorkRaveStats 4\n\
+                       D 8 10 122 9 0 8 10 122 17 0\n\
+                       B 1 3 4 2 1 \n\
+                       B 0 7 1 1 1 1 1 1 1 \n\
+                       B 0 7 2 2 2 2 2 2 2 \n\
+                       m 6 100 0 0 0 0 10 \n\
+                       D 27 2 122 1 30 27 2 122 3 0\n\
+                       m 6 100 0 0 0 0 20 \n";
+        let options = LoadOptions { timezone: london(), ..LoadOptions::default() };
+        let loaded = History::load_historystats_reader(content.as_bytes(), &options).unwrap();
+        let mut output: Vec<u8> = vec![];
+        loaded.history.write_historystats(&mut output).unwrap();
+
+        assert_eq!(String::from_utf8(output).unwrap(), "WorkRaveStats 4\n\
+                                                        D 27 2 122 2 30 27 2 122 3 0\n\
+                                                        m 6 100 0 0 0 0 20 \n\
+                                                        D 8 10 122 9 0 8 10 122 17 0\n\
+                                                        B 0 7 2 2 2 2 2 2 2 \n\
+                                                        B 1 7 4 2 1 0 0 0 0 \n\
+                                                        m 6 100 0 0 0 0 10 \n");
+    }
+
+    #[test]
+    fn test_write_historystats_round_trip_v3_crlf() {
+        let content = "WorkRaveStats 3\r\n\
+                       D 31 11 121 8 0 31 11 121 12 15\r\n\
+                       B 0 7 3 2 1 0 0 0 0 \r\n\
+                       m 5 4000 12000 9000 300 210 \r\n";
+
+        assert_eq!(round_trip(content), content);
+    }
+
+    #[test]
+    fn test_load_historystats_keeps_sessions_on_same_day() {
+        let content = "WorkRaveStats 4\n\
+                       D 8 10 122 9 0 8 10 122 12 30\n\
+                       B 0 7 3 2 1 0 0 0 0 \n\
+                       m 6 338 28584 40231 29 104 33 \n\
+                       D 8 10 122 13 15 8 10 122 18 0\n\
+                       B 0 7 5 1 2 1 1 0 0 \n\
+                       m 6 662 14296 2649 31 96 67 \n";
+        let loaded = History::load_historystats_reader(content.as_bytes(), &LoadOptions::default()).unwrap();
+        let day = loaded.history.days.get(&NaiveDate::from_ymd_opt(2022, 11, 8).unwrap()).unwrap();
+
+        assert_eq!(day.sessions.len(), 2);
+        assert_eq!(day.stats.total_active_time_seconds, 1000);
+        assert_eq!(day.stats.total_keystrokes, 100);
+        assert_eq!(day.stats.total_mouse_movement, 42880);
+        assert_eq!(day.breaks.get(&BreakType::MicroBreak).unwrap().prompted, 8);
+        assert_eq!(day.datetime_range.start(), day.sessions[0].datetime_range.start());
+        assert_eq!(day.datetime_range.end(), day.sessions[1].datetime_range.end());
+        assert_eq!(round_trip(content), content);
+    }
+
+    #[test]
+    fn test_load_historystats_day_start() {
+        let content = "WorkRaveStats 4\n\
+                       D 8 10 122 9 0 8 10 122 23 30\n\
+                       m 6 338 28584 40231 29 104 33 \n\
+                       D 9 10 122 1 15 9 10 122 3 0\n\
+                       m 6 662 14296 2649 31 96 67 \n\
+                       D 9 10 122 4 0 9 10 122 9 0\n\
+                       m 6 100 0 0 0 0 5 \n";
+        let options = LoadOptions {
+            day_start: NaiveTime::from_hms_opt(4, 0, 0).unwrap(),
+            ..LoadOptions::default()
+        };
+        let loaded = History::load_historystats_reader(content.as_bytes(), &options).unwrap();
+
+        let night_shift = loaded.history.days.get(&NaiveDate::from_ymd_opt(2022, 11, 8).unwrap()).unwrap();
+        assert_eq!(night_shift.sessions.len(), 2);
+        assert_eq!(night_shift.stats.total_keystrokes, 100);
+
+        let next_day = loaded.history.days.get(&NaiveDate::from_ymd_opt(2022, 11, 9).unwrap()).unwrap();
+        assert_eq!(next_day.stats.total_keystrokes, 5);
+    }
+
+    #[test]
+    fn test_history_range_queries() {
+        let content = "WorkRaveStats 4\n\
+                       D 9 10 122 9 0 9 10 122 17 0\n\
+                       m 6 300 0 0 0 0 3 \n\
+                       D 7 10 122 9 0 7 10 122 17 0\n\
+                       m 6 100 0 0 0 0 1 \n\
+                       D 8 10 122 9 0 8 10 122 17 0\n\
+                       m 6 200 0 0 0 0 2 \n";
+        let history = History::load_historystats_reader(content.as_bytes(), &LoadOptions::default()).unwrap().history;
+        let date = |day: u32| NaiveDate::from_ymd_opt(2022, 11, day).unwrap();
+
+        let ordered: Vec<NaiveDate> = history.iter().map(|(date, _)| *date).collect();
+        assert_eq!(ordered, vec![date(7), date(8), date(9)]);
+
+        let in_range: Vec<u64> = history.range(date(8)..date(10)).map(|(_, day)| day.stats.total_keystrokes).collect();
+        assert_eq!(in_range, vec![2, 3]);
+
+        assert_eq!(history.first_day().unwrap().0, &date(7));
+        assert_eq!(history.last_day().unwrap().0, &date(9));
+        assert_eq!(history.get(&date(8)).unwrap().stats.total_active_time_seconds, 200);
+        assert!(history.get(&date(10)).is_none());
+    }
+
+    #[test]
+    fn test_history_rollup() {
+        let content = "WorkRaveStats 4\n\
+                       D 5 10 122 9 0 5 10 122 17 0\n\
+                       m 6 100 0 0 0 0 10 \n\
+                       D 6 10 122 9 0 6 10 122 17 0\n\
+                       m 6 100 0 0 0 0 20 \n\
+                       D 7 10 122 9 0 7 10 122 17 0\n\
+                       m 6 100 0 0 0 0 30 \n\
+                       D 8 10 122 9 0 8 10 122 17 0\n\
+                       m 6 100 0 0 0 0 100 \n\
+                       D 1 11 122 9 0 1 11 122 17 0\n\
+                       m 6 100 0 0 0 0 7 \n";
+        let history = History::load_historystats_reader(content.as_bytes(), &LoadOptions::default()).unwrap().history;
+        let date = |month: u32, day: u32| NaiveDate::from_ymd_opt(2022, month, day).unwrap();
+
+        // 05-11-2022 is a Saturday
+        let weeks = history.rollup(Granularity::Week, Weekday::Mon);
+        assert_eq!(weeks.len(), 5);
+        assert_eq!((weeks[0].start, weeks[0].end), (date(10, 31), date(11, 6)));
+        assert_eq!(weeks[0].sum.total_keystrokes, 30);
+        assert_eq!((weeks[1].start, weeks[1].day_count, weeks[1].missing_days), (date(11, 7), 2, 5));
+        assert_eq!((weeks[2].day_count, weeks[2].missing_days), (0, 7));
+
+        let sunday_weeks = history.rollup(Granularity::Week, Weekday::Sun);
+        assert_eq!(sunday_weeks[1].start, date(11, 6));
+        assert_eq!(sunday_weeks[1].sum.total_keystrokes, 150);
+
+        let months = history.rollup(Granularity::Month, Weekday::Mon);
+        assert_eq!(months.len(), 2);
+        assert_eq!((months[0].start, months[0].end), (date(11, 1), date(11, 30)));
+        assert_eq!(months[0].day_count, 4);
+        assert_eq!(months[0].missing_days, 22);
+        assert_eq!(months[0].mean(StatsField::Keystrokes, GapPolicy::Exclude), 40.0);
+        assert_eq!(months[0].mean(StatsField::Keystrokes, GapPolicy::CountAsZero), 160.0 / 26.0);
+        assert_eq!(months[0].median(StatsField::Keystrokes, GapPolicy::Exclude), 25.0);
+        assert_eq!(months[0].median(StatsField::Keystrokes, GapPolicy::CountAsZero), 0.0);
+        assert_eq!(months[1].median(StatsField::Keystrokes, GapPolicy::Exclude), 7.0);
+
+        let years = history.rollup(Granularity::Year, Weekday::Mon);
+        assert_eq!(years.len(), 1);
+        assert_eq!(years[0].end, date(12, 31));
+        assert_eq!(years[0].sum.total_active_time_seconds, 500);
+    }
+
+    #[test]
+    fn test_activity_rates() {
+        let content = "WorkRaveStats 4\n\
+                       D 8 10 122 9 0 8 10 122 10 0\n\
+                       m 6 1200 6000 0 60 200 1000 \n\
+                       D 8 10 122 13 0 8 10 122 14 0\n\
+                       m 6 1200 0 0 0 0 1400 \n";
+        let history = History::load_historystats_reader(content.as_bytes(), &LoadOptions::default()).unwrap().history;
+        let day = history.get(&NaiveDate::from_ymd_opt(2022, 11, 8).unwrap()).unwrap();
+
+        assert_eq!(day.recorded_seconds(), 7200);
+        let rates = day.rates();
+        assert_eq!(rates.keystrokes_per_active_minute, 60.0);
+        assert_eq!(rates.clicks_per_active_hour, 300.0);
+        assert_eq!(rates.mouse_speed, 100.0);
+        assert!((rates.idle_ratio - 2.0 / 3.0).abs() < 1e-9);
+
+        assert_eq!(ActivityRates::new(&InputStats::default(), 0), ActivityRates::default());
+    }
+
+    #[test]
+    fn test_history_missing_dates() {
+        let content = "WorkRaveStats 4\n\
+                       D 5 10 122 9 0 5 10 122 17 0\n\
+                       m 6 100 0 0 0 0 10 \n\
+                       D 8 10 122 9 0 8 10 122 17 0\n\
+                       m 6 0 0 0 0 0 0 \n";
+        let history = History::load_historystats_reader(content.as_bytes(), &LoadOptions::default()).unwrap().history;
+        let date = |day: u32| NaiveDate::from_ymd_opt(2022, 11, day).unwrap();
+
+        // A recorded day with no activity is not a missing day
+        assert_eq!(history.missing_dates(), vec![date(6), date(7)]);
+    }
+
+    #[test]
+    fn test_load_consistently_retries_reads_of_changing_files() {
+        let path = std::env::temp_dir().join(format!("informant-consistent-{}", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        fs::write(&path, "WorkRaveStats 4\nD 8 10 122 9 0 8 10 122 17 0\n").unwrap();
+        let mut attempts = 0;
+        let loaded = History::load_consistently(&path, 3, std::time::Duration::ZERO, || {
+            attempts += 1;
+            let loaded = History::load_historystats(&path, &LoadOptions::default());
+            // Workrave finishes the day while the first read is running
+            if attempts == 1 {
+                fs::write(&path, "WorkRaveStats 4\nD 8 10 122 9 0 8 10 122 17 0\nm 6 0 0 0 0 0 0 \n").unwrap();
+            }
+            loaded
+        }).unwrap();
+        assert_eq!(attempts, 2);
+        assert_eq!(loaded.history.days.len(), 1);
+
+        // A file that ends partway through a day but isn't changing is only read once
+        fs::write(&path, "WorkRaveStats 4\nD 8 10 122 9 0 8 10 122 17 0\n").unwrap();
+        let mut attempts = 0;
+        let loaded = History::load_consistently(&path, 3, std::time::Duration::ZERO, || {
+            attempts += 1;
+            History::load_historystats(&path, &LoadOptions::default())
+        }).unwrap();
+        assert_eq!(attempts, 1);
+        assert_eq!(loaded.unfinished_warnings, 1);
+
+        fs::write(&path, "WorkRaveStats 4\nD 8 10 122 9 0 8 10 122 17 0\nm 6 0 0 0 0 0 0 \n").unwrap();
+        let mut attempts = 0;
+        let loaded = History::load_consistently(&path, 3, std::time::Duration::ZERO, || {
+            attempts += 1;
+            History::load_historystats(&path, &LoadOptions::default())
+        }).unwrap();
+        assert_eq!(attempts, 1);
+        assert_eq!(loaded.history.days.len(), 1);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_history_trailing_rollup() {
+        let content = "WorkRaveStats 4\n\
+                       D 5 10 122 9 0 5 10 122 17 0\n\
+                       m 6 100 0 0 0 0 10 \n\
+                       D 7 10 122 9 0 7 10 122 17 0\n\
+                       m 6 300 0 0 0 0 30 \n\
+                       D 9 10 122 9 0 9 10 122 17 0\n\
+                       m 6 500 0 0 0 0 50 \n";
+        let history = History::load_historystats_reader(content.as_bytes(), &LoadOptions::default()).unwrap().history;
+        let date = |day: u32| NaiveDate::from_ymd_opt(2022, 11, day).unwrap();
+
+        // 3-11 and 4-11 come before the first recorded day, so only 6-11 and 8-11 are missing
+        let rollup = history.trailing_rollup(date(9), 6);
+        assert_eq!((rollup.start, rollup.end), (date(3), date(8)));
+        assert_eq!((rollup.day_count, rollup.missing_days), (2, 2));
+        assert_eq!(rollup.mean(StatsField::Keystrokes, GapPolicy::Exclude), 20.0);
+        assert_eq!(rollup.mean(StatsField::Keystrokes, GapPolicy::CountAsZero), 10.0);
+    }
+
+    fn load_source(content: &str, source: &str) -> History {
+        let mut history = History::load_historystats_reader(content.as_bytes(), &LoadOptions::default()).unwrap().history;
+        history.set_source(source);
+        history
+    }
+
+    #[test]
+    fn test_history_merge_sources() {
+        let mut history = load_source("WorkRaveStats 4\n\
+                                       D 5 10 122 9 0 5 10 122 17 0\n\
+                                       m 6 100 0 0 0 0 10 \n\
+                                       D 7 10 122 9 0 7 10 122 17 0\n\
+                                       m 6 100 0 0 0 0 30 \n", "desktop");
+        history.merge(load_source("WorkRaveStats 4\n\
+                                   D 7 10 122 18 0 7 10 122 20 0\n\
+                                   m 6 50 0 0 0 0 5 \n\
+                                   D 8 10 122 9 0 8 10 122 17 0\n\
+                                   m 6 50 0 0 0 0 7 \n", "laptop"));
+        let date = |day: u32| NaiveDate::from_ymd_opt(2022, 11, day).unwrap();
+
+        let day = history.get(&date(7)).unwrap();
+        assert_eq!(day.stats.total_keystrokes, 35);
+        assert_eq!(day.for_source("desktop").unwrap().stats.total_keystrokes, 30);
+        assert_eq!(day.for_source("laptop").unwrap().stats.total_keystrokes, 5);
+        assert!(history.get(&date(5)).unwrap().for_source("laptop").is_none());
+        assert_eq!(history.sources(), vec!["desktop", "laptop"]);
+
+        let laptop = history.source_rollup(Granularity::Day, Weekday::Mon, "laptop");
+        assert_eq!(laptop.len(), 4);
+        assert_eq!(laptop.iter().map(|rollup| rollup.sum.total_keystrokes).collect::<Vec<_>>(), vec![0, 0, 5, 7]);
+        assert_eq!(laptop.iter().map(|rollup| rollup.missing_days).collect::<Vec<_>>(), vec![1, 1, 0, 0]);
+    }
+
+    #[test]
+    fn test_merge_todaystats_keeps_other_sources() {
+        let mut history = load_source("WorkRaveStats 4\n\
+                                       D 7 10 122 9 0 7 10 122 12 0\n\
+                                       m 6 100 0 0 0 0 30 \n", "desktop");
+        history.merge(load_source("WorkRaveStats 4\n\
+                                   D 7 10 122 13 0 7 10 122 14 0\n\
+                                   m 6 50 0 0 0 0 5 \n", "laptop"));
+
+        history.merge_todaystats(load_source("WorkRaveStats 4\n\
+                                              D 7 10 122 9 0 7 10 122 15 0\n\
+                                              m 6 200 0 0 0 0 40 \n", "desktop"));
+        let day = history.get(&NaiveDate::from_ymd_opt(2022, 11, 7).unwrap()).unwrap();
+        assert_eq!(day.sessions.len(), 2);
+        assert_eq!(day.stats.total_keystrokes, 45);
+        assert_eq!(day.for_source("desktop").unwrap().stats.total_keystrokes, 40);
+    }
+
+    #[test]
+    fn test_merge_todaystats_keeps_earlier_sessions_of_the_workday() {
+        let options = LoadOptions {
+            day_start: NaiveTime::from_hms_opt(4, 0, 0).unwrap(),
+            ..LoadOptions::default()
+        };
+        let mut history = History::load_historystats_reader("WorkRaveStats 4\n\
+                                                                     D 8 10 122 9 0 8 10 122 23 30\n\
+                                                                     m 6 3600 0 0 0 0 900 \n".as_bytes(), &options).unwrap().history;
+        let today = "WorkRaveStats 4\n\
+                     D 9 10 122 1 15 9 10 122 2 0\n\
+                     m 6 600 0 0 0 0 50 \n";
+        history.merge_todaystats(History::load_historystats_reader(today.as_bytes(), &options).unwrap().history);
+        // Reloading todaystats replaces what it merged before
+        history.merge_todaystats(History::load_historystats_reader(today.as_bytes(), &options).unwrap().history);
+
+        let day = history.get(&NaiveDate::from_ymd_opt(2022, 11, 8).unwrap()).unwrap();
+        assert_eq!(day.sessions.len(), 2);
+        assert_eq!(day.stats.total_keystrokes, 950);
+    }
+
+    #[test]
+    fn test_convert_movement() {
+        let mut history = load_source("WorkRaveStats 4\n\
+                                       D 7 10 122 9 0 7 10 122 17 0\n\
+                                       m 6 100 1000 300 0 0 0 \n", "laptop");
+        let laptop = MovementCalibration { pixels_per_meter: 2000.0 };
+        history.convert_movement(&laptop, &MovementCalibration { pixels_per_meter: 4000.0 });
+        history.merge(load_source("WorkRaveStats 4\n\
+                                   D 7 10 122 18 0 7 10 122 20 0\n\
+                                   m 6 50 500 0 0 0 0 \n", "desktop"));
+
+        let day = history.get(&NaiveDate::from_ymd_opt(2022, 11, 7).unwrap()).unwrap();
+        assert_eq!(day.for_source("laptop").unwrap().stats.total_mouse_movement, 2000);
+        assert_eq!(day.for_source("laptop").unwrap().stats.total_mouse_click_movement, 600);
+        assert_eq!(day.stats.total_mouse_movement, 2500);
     }
 }